@@ -0,0 +1,488 @@
+//! Command-line interface of tags-sync.
+//!
+//! Every setting is given by a flag or by its environment variable, which is
+//! how `action.yml` configures the commands.
+
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use regex::Regex;
+
+use crate::{
+    context::TagsApi,
+    utils::{BranchTemplate, FailurePolicy, HostSettings},
+};
+
+const ENV_HELP: &str = "\
+The GITHUB_TOKEN is only given by its environment variable.
+
+A pair of the configuration file with a base-env-prefix or head-env-prefix reads
+the instance hosting its repository from the environment variables with that
+prefix instead of BASE or HEAD, e.g. GHE_WEB_URL or GHE_GIT_PASSWORD.";
+
+/// Syncs the tags of a base repository to a head repository as branches.
+#[derive(Debug, Parser)]
+#[command(version, after_help = ENV_HELP)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Detects the new tags of the base repository, and writes them to the
+    /// new tags file for `sync`.
+    Detect(Handoff),
+
+    /// Syncs the tags listed in the new tags file written by `detect` to the
+    /// head repository as branches.
+    Sync(Handoff),
+
+    /// Detects the new tags and syncs them at once.
+    Run(Config),
+
+    /// Shows which tags are synced, which are pending, and which synced
+    /// branches have lost their tag.
+    Status(Config),
+
+    /// Deletes the synced branches whose tag no longer exists in the base
+    /// repository.
+    Prune(Config),
+}
+
+impl Command {
+    pub fn config(&self) -> &Config {
+        match self {
+            Self::Detect(handoff) | Self::Sync(handoff) => &handoff.config,
+            Self::Run(config) | Self::Status(config) | Self::Prune(config) => config,
+        }
+    }
+}
+
+/// Settings of the commands handing the new tags over through a file.
+#[derive(Debug, Args)]
+pub struct Handoff {
+    #[command(flatten)]
+    pub config: Config,
+
+    /// JSON file listing the new tags with their commit and pair, under the
+    /// version of its format [default: <WORKSPACE>/new_tags.json]
+    #[arg(long, env = "NEW_TAGS_FILE")]
+    pub new_tags_file: Option<PathBuf>,
+}
+
+/// Settings of the repositories and of what is done for each synced tag.
+///
+/// The optional settings given as empty strings are ignored, as the action
+/// passes all of its inputs whether they are set or not.
+#[derive(Debug, Clone, Args)]
+pub struct Config {
+    /// File defining many pairs of base and head repositories to sync, whose
+    /// settings override the other ones
+    #[arg(long = "config", env = "CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Name of the pair of repositories in the configuration file, if any.
+    #[arg(skip)]
+    pub pair: Option<String>,
+
+    /// Base (upstream) repository, e.g. `torvalds/linux`,
+    /// `gitlab:group/project`, `gitea:owner/name` or a git URL
+    #[arg(
+        long = "base-repository",
+        env = "BASE_REPO",
+        required_unless_present = "config_file"
+    )]
+    pub base_repo: Option<String>,
+
+    /// Head (fork) repository, in the same format as the base repository
+    #[arg(
+        long = "head-repository",
+        env = "HEAD_REPO",
+        required_unless_present = "config_file"
+    )]
+    pub head_repo: Option<String>,
+
+    /// Web URL of the GitHub instance hosting the base repository
+    /// [default: https://github.com]
+    #[arg(long, env = "BASE_WEB_URL")]
+    pub base_web_url: Option<String>,
+
+    /// API URL of the GitHub instance hosting the base repository
+    /// [default: derived from the web URL]
+    #[arg(long, env = "BASE_API_URL")]
+    pub base_api_url: Option<String>,
+
+    /// Token of the GitHub instance hosting the base repository, instead of
+    /// the GITHUB_TOKEN
+    #[arg(long, env = "BASE_GITHUB_TOKEN", hide_env_values = true)]
+    pub base_github_token: Option<String>,
+
+    /// Web URL of the GitLab instance hosting the base repository
+    /// [default: https://gitlab.com]
+    #[arg(long, env = "BASE_GITLAB_URL")]
+    pub base_gitlab_url: Option<String>,
+
+    /// API URL of the GitLab instance hosting the base repository
+    /// [default: derived from the web URL]
+    #[arg(long, env = "BASE_GITLAB_API_URL")]
+    pub base_gitlab_api_url: Option<String>,
+
+    /// Token of the GitLab instance hosting the base repository, instead of
+    /// the shared GitLab token
+    #[arg(long, env = "BASE_GITLAB_TOKEN", hide_env_values = true)]
+    pub base_gitlab_token: Option<String>,
+
+    /// Web URL of the Gitea instance hosting the base repository
+    #[arg(long, env = "BASE_GITEA_URL")]
+    pub base_gitea_url: Option<String>,
+
+    /// API URL of the Gitea instance hosting the base repository
+    /// [default: derived from the web URL]
+    #[arg(long, env = "BASE_GITEA_API_URL")]
+    pub base_gitea_api_url: Option<String>,
+
+    /// Token of the Gitea instance hosting the base repository, instead of
+    /// the shared Gitea token
+    #[arg(long, env = "BASE_GITEA_TOKEN", hide_env_values = true)]
+    pub base_gitea_token: Option<String>,
+
+    /// User name to access the base repository when given by a git URL
+    #[arg(long, env = "BASE_GIT_USERNAME")]
+    pub base_git_username: Option<String>,
+
+    /// Password to access the base repository when given by a git URL
+    #[arg(long, env = "BASE_GIT_PASSWORD", hide_env_values = true)]
+    pub base_git_password: Option<String>,
+
+    /// Web URL of the GitHub instance hosting the head repository
+    /// [default: https://github.com]
+    #[arg(long, env = "HEAD_WEB_URL")]
+    pub head_web_url: Option<String>,
+
+    /// API URL of the GitHub instance hosting the head repository
+    /// [default: derived from the web URL]
+    #[arg(long, env = "HEAD_API_URL")]
+    pub head_api_url: Option<String>,
+
+    /// Token of the GitHub instance hosting the head repository, instead of
+    /// the GITHUB_TOKEN
+    #[arg(long, env = "HEAD_GITHUB_TOKEN", hide_env_values = true)]
+    pub head_github_token: Option<String>,
+
+    /// Web URL of the GitLab instance hosting the head repository
+    /// [default: https://gitlab.com]
+    #[arg(long, env = "HEAD_GITLAB_URL")]
+    pub head_gitlab_url: Option<String>,
+
+    /// API URL of the GitLab instance hosting the head repository
+    /// [default: derived from the web URL]
+    #[arg(long, env = "HEAD_GITLAB_API_URL")]
+    pub head_gitlab_api_url: Option<String>,
+
+    /// Token of the GitLab instance hosting the head repository, instead of
+    /// the shared GitLab token
+    #[arg(long, env = "HEAD_GITLAB_TOKEN", hide_env_values = true)]
+    pub head_gitlab_token: Option<String>,
+
+    /// Web URL of the Gitea instance hosting the head repository
+    #[arg(long, env = "HEAD_GITEA_URL")]
+    pub head_gitea_url: Option<String>,
+
+    /// API URL of the Gitea instance hosting the head repository
+    /// [default: derived from the web URL]
+    #[arg(long, env = "HEAD_GITEA_API_URL")]
+    pub head_gitea_api_url: Option<String>,
+
+    /// Token of the Gitea instance hosting the head repository, instead of
+    /// the shared Gitea token
+    #[arg(long, env = "HEAD_GITEA_TOKEN", hide_env_values = true)]
+    pub head_gitea_token: Option<String>,
+
+    /// User name to access the head repository when given by a git URL
+    #[arg(long, env = "HEAD_GIT_USERNAME")]
+    pub head_git_username: Option<String>,
+
+    /// Password to access the head repository when given by a git URL
+    #[arg(long, env = "HEAD_GIT_PASSWORD", hide_env_values = true)]
+    pub head_git_password: Option<String>,
+
+    /// Token of the GitLab instances, unless one is given for the instance
+    #[arg(long, env = "GITLAB_TOKEN", hide_env_values = true)]
+    pub gitlab_token: Option<String>,
+
+    /// Token of the Gitea instances, unless one is given for the instance
+    #[arg(long, env = "GITEA_TOKEN", hide_env_values = true)]
+    pub gitea_token: Option<String>,
+
+    /// Prefix of the environment variables configuring the instance hosting
+    /// the base repository instead of the flags, as set by a pair of the
+    /// configuration file.
+    #[arg(skip)]
+    pub base_env_prefix: Option<String>,
+
+    /// Prefix of the environment variables configuring the instance hosting
+    /// the head repository instead of the flags, as set by a pair of the
+    /// configuration file.
+    #[arg(skip)]
+    pub head_env_prefix: Option<String>,
+
+    /// Directory where the files of tags-sync are written
+    #[arg(long, env = "GITHUB_WORKSPACE", default_value = ".")]
+    pub workspace: PathBuf,
+
+    /// Path under the workspace to clone the head repository
+    #[arg(long, env = "CLONED_PATH", default_value = "head-repo")]
+    pub cloned_path: PathBuf,
+
+    /// Regular expression matching the tags to sync
+    #[arg(long, env = "FILTER_TAGS", default_value = ".*")]
+    pub filter_tags: Regex,
+
+    /// API used to list the tags of the base repository, either `rest` or
+    /// `graphql`
+    #[arg(long, env = "LIST_TAGS_API", default_value = "rest")]
+    pub list_tags_api: TagsApi,
+
+    /// Name of the branch synced from each tag, where `{tag}` is replaced
+    /// with the name of the tag
+    #[arg(long, env = "BRANCH_TEMPLATE", default_value_t)]
+    pub branch_template: BranchTemplate,
+
+    /// URL of the patch file to apply to each synced branch
+    #[arg(long = "apply-patch", env = "PATCH_URL")]
+    pub patch_url: Option<String>,
+
+    /// Commit message of the applied patch [default: Apply patch from <URL>]
+    #[arg(long, env = "PATCH_MESSAGE")]
+    pub patch_message: Option<String>,
+
+    /// Author of the applied patch
+    #[arg(long, env = "PATCH_AUTHOR", default_value = "github-actions[bot]")]
+    pub patch_author: String,
+
+    /// Email of the author of the applied patch
+    #[arg(
+        long,
+        env = "PATCH_AUTHOR_EMAIL",
+        default_value = "github-actions[bot]@users.noreply.github.com"
+    )]
+    pub patch_author_email: String,
+
+    /// Committer of the applied patch
+    #[arg(long, env = "PATCH_COMMITTER", default_value = "github-actions[bot]")]
+    pub patch_committer: String,
+
+    /// Email of the committer of the applied patch
+    #[arg(
+        long,
+        env = "PATCH_COMMITTER_EMAIL",
+        default_value = "github-actions[bot]@users.noreply.github.com"
+    )]
+    pub patch_committer_email: String,
+
+    /// Branch of the head repository to open a pull request against for each
+    /// synced branch
+    #[arg(long, env = "PULL_REQUEST_BASE")]
+    pub pull_request_base: Option<String>,
+
+    /// Title of the pull requests, with the `{tag}`, `{branch}`,
+    /// `{compare_url}` and `{patch}` placeholders
+    #[arg(long, env = "PULL_REQUEST_TITLE")]
+    pub pull_request_title: Option<String>,
+
+    /// Body of the pull requests, with the same placeholders as the title
+    #[arg(long, env = "PULL_REQUEST_BODY")]
+    pub pull_request_body: Option<String>,
+
+    /// Comma separated labels to add to the pull requests
+    #[arg(long, env = "PULL_REQUEST_LABELS", default_value = "")]
+    pub pull_request_labels: String,
+
+    /// Comma separated user names to assign the pull requests to
+    #[arg(long, env = "PULL_REQUEST_ASSIGNEES", default_value = "")]
+    pub pull_request_assignees: String,
+
+    /// Comma separated user names to request reviews of the pull requests
+    /// from
+    #[arg(long, env = "PULL_REQUEST_REVIEWERS", default_value = "")]
+    pub pull_request_reviewers: String,
+
+    /// Create a release in the head repository for each synced tag
+    #[arg(long, env = "CREATE_RELEASE")]
+    pub create_release: bool,
+
+    /// What to do when a tag fails to sync: `fail-fast` skips the later tags,
+    /// `fail-at-end` syncs them before failing, and `best-effort` syncs them
+    /// and only warns about the failing ones
+    #[arg(long, env = "ON_FAILURE", default_value = "fail-fast")]
+    pub on_failure: FailurePolicy,
+
+    /// Open an issue in the head repository for each tag that fails to sync,
+    /// and close it once the tag is synced
+    #[arg(long, env = "FAILURE_ISSUES")]
+    pub open_issue_on_failure: bool,
+
+    /// Publish a check run with the outcome of each tag to the head
+    /// repository, which must be on GitHub
+    #[arg(long, env = "CHECK_RUN")]
+    pub check_run: bool,
+
+    /// Set a commit status on each synced branch, telling how the patch was
+    /// applied
+    #[arg(long, env = "COMMIT_STATUS")]
+    pub commit_status: bool,
+
+    /// Sync the tags in the local clone only, without pushing the branches
+    /// nor running anything after the push, and report what would be synced
+    #[arg(long, env = "DRY_RUN")]
+    pub dry_run: bool,
+
+    /// ID of the GitHub App to authenticate as, instead of the GITHUB_TOKEN
+    #[arg(long, env = "GITHUB_APP_ID")]
+    pub app_id: Option<String>,
+
+    /// PEM encoded private key of the GitHub App
+    #[arg(long, env = "GITHUB_APP_PRIVATE_KEY", hide_env_values = true)]
+    pub app_private_key: Option<String>,
+
+    /// ID of the installation of the GitHub App on the head repository,
+    /// which is looked up if not given
+    #[arg(long, env = "GITHUB_APP_INSTALLATION_ID")]
+    pub app_installation_id: Option<String>,
+
+    /// Maximum number of attempts of each network operation, including the
+    /// first one
+    #[arg(long, env = "RETRY_ATTEMPTS", default_value_t = 3)]
+    pub retry_attempts: u32,
+
+    /// Delay in milliseconds before the first retry, doubled on each
+    /// subsequent retry
+    #[arg(long, env = "RETRY_DELAY_MS", default_value_t = 1000)]
+    pub retry_delay_ms: u64,
+
+    /// Upper bound in milliseconds of the delay between two attempts
+    #[arg(long, env = "RETRY_MAX_DELAY_MS", default_value_t = 30_000)]
+    pub retry_max_delay_ms: u64,
+
+    /// Maximum number of seconds to wait for the GitHub API rate limit to
+    /// reset
+    #[arg(long, env = "RATE_LIMIT_MAX_WAIT", default_value_t = 600)]
+    pub rate_limit_max_wait: u64,
+
+    /// Number of seconds after which the lock taken on the head repository is
+    /// considered stale
+    #[arg(long, env = "LOCK_TIMEOUT", default_value_t = 3600)]
+    pub lock_timeout: u64,
+}
+
+impl Config {
+    /// Returns the settings of the instance hosting the base repository.
+    pub fn base_host(&self) -> HostSettings {
+        self.host(
+            &self.base_env_prefix,
+            HostSettings {
+                web_url: self.base_web_url.clone(),
+                api_url: self.base_api_url.clone(),
+                github_token: self.base_github_token.clone(),
+                gitlab_url: self.base_gitlab_url.clone(),
+                gitlab_api_url: self.base_gitlab_api_url.clone(),
+                gitlab_token: self.base_gitlab_token.clone(),
+                gitea_url: self.base_gitea_url.clone(),
+                gitea_api_url: self.base_gitea_api_url.clone(),
+                gitea_token: self.base_gitea_token.clone(),
+                git_username: self.base_git_username.clone(),
+                git_password: self.base_git_password.clone(),
+            },
+        )
+    }
+
+    /// Returns the settings of the instance hosting the head repository.
+    pub fn head_host(&self) -> HostSettings {
+        self.host(
+            &self.head_env_prefix,
+            HostSettings {
+                web_url: self.head_web_url.clone(),
+                api_url: self.head_api_url.clone(),
+                github_token: self.head_github_token.clone(),
+                gitlab_url: self.head_gitlab_url.clone(),
+                gitlab_api_url: self.head_gitlab_api_url.clone(),
+                gitlab_token: self.head_gitlab_token.clone(),
+                gitea_url: self.head_gitea_url.clone(),
+                gitea_api_url: self.head_gitea_api_url.clone(),
+                gitea_token: self.head_gitea_token.clone(),
+                git_username: self.head_git_username.clone(),
+                git_password: self.head_git_password.clone(),
+            },
+        )
+    }
+
+    /// Returns the settings given by the `flags` of a side, or by the
+    /// environment variables starting with its `prefix` if any, falling back
+    /// to the tokens shared by all instances.
+    fn host(&self, prefix: &Option<String>, flags: HostSettings) -> HostSettings {
+        let mut host = match prefix {
+            Some(prefix) => HostSettings::from_env(prefix),
+            None => flags,
+        };
+        if non_empty(&host.gitlab_token).is_none() {
+            host.gitlab_token = self.gitlab_token.clone();
+        }
+        if non_empty(&host.gitea_token).is_none() {
+            host.gitea_token = self.gitea_token.clone();
+        }
+        host
+    }
+}
+
+/// Returns the `value` of an optional setting, unless it is empty.
+pub fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(parse_flags {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "tags-sync",
+            "sync",
+            "--base-repository",
+            "torvalds/linux",
+            "--head-repository",
+            "gitlab:group/linux",
+            "--filter-tags",
+            "^v6",
+            "--list-tags-api",
+            "graphql",
+            "--check-run",
+            "--dry-run",
+            "--on-failure",
+            "fail-at-end",
+            "--new-tags-file",
+            "tags.json",
+        ])?;
+        let handoff = match &cli.command {
+            Command::Sync(handoff) => handoff,
+            command => panic!("Unexpected command {command:?}"),
+        };
+        let config = cli.command.config();
+        assert_eq!(handoff.new_tags_file, Some(PathBuf::from("tags.json")));
+        assert_eq!(config.head_repo.as_deref(), Some("gitlab:group/linux"));
+        assert_eq!(config.branch_template.branch("v6.0"), "sync-v6.0");
+        assert!(config.filter_tags.is_match("v6.0"));
+        assert!(matches!(config.list_tags_api, TagsApi::GraphQl));
+        assert!(config.check_run);
+        assert!(!config.commit_status);
+        assert!(config.dry_run);
+        assert_eq!(config.on_failure, FailurePolicy::FailAtEnd);
+        assert_eq!(config.patch_author, "github-actions[bot]");
+        assert_eq!(non_empty(&config.patch_url), None);
+    });
+}
@@ -0,0 +1,295 @@
+//! Configuration file defining many pairs of base and head repositories,
+//! e.g. `.github/tags-sync.toml`:
+//!
+//! ```toml
+//! # Settings shared by all pairs
+//! [defaults]
+//! filter-tags = "^v"
+//! pull-request-base = "main"
+//!
+//! [[pairs]]
+//! name = "linux"
+//! base-repository = "torvalds/linux"
+//! head-repository = "Rust-for-Linux/linux"
+//! apply-patch = "https://example.com/linux.patch"
+//!
+//! [[pairs]]
+//! name = "git"
+//! base-repository = "https://git.kernel.org/pub/scm/git/git.git"
+//! head-repository = "gitlab:me/git"
+//! branch-template = "upstream/{tag}"
+//! create-release = true
+//! ```
+//!
+//! The keys are the same as the flags, and a YAML file is read as well if its
+//! extension is `.yml` or `.yaml`.
+//!
+//! The instances and credentials are not read from the file, as they are
+//! secrets, but a pair on other instances can read them from the environment
+//! variables with another prefix than `BASE` or `HEAD`, e.g.
+//! `head-env-prefix = "GHE"` for `GHE_WEB_URL` and `GHE_GITHUB_TOKEN`.
+
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::cli::Config;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    defaults: Settings,
+    pairs: Vec<Settings>,
+}
+
+/// Settings of a pair of repositories, or of all of them, which override the
+/// ones given by the flags or the environment.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Settings {
+    /// Name identifying the pair in the logs and the results, which is also
+    /// the directory of its clone.
+    name: Option<String>,
+    base_repository: Option<String>,
+    head_repository: Option<String>,
+    /// Prefix of the environment variables of the instance of the base
+    /// repository, instead of the flags.
+    base_env_prefix: Option<String>,
+    /// Prefix of the environment variables of the instance of the head
+    /// repository, instead of the flags.
+    head_env_prefix: Option<String>,
+    filter_tags: Option<String>,
+    list_tags_api: Option<String>,
+    branch_template: Option<String>,
+    apply_patch: Option<String>,
+    patch_message: Option<String>,
+    patch_author: Option<String>,
+    patch_author_email: Option<String>,
+    patch_committer: Option<String>,
+    patch_committer_email: Option<String>,
+    pull_request_base: Option<String>,
+    pull_request_title: Option<String>,
+    pull_request_body: Option<String>,
+    pull_request_labels: Option<Vec<String>>,
+    pull_request_assignees: Option<Vec<String>>,
+    pull_request_reviewers: Option<Vec<String>>,
+    create_release: Option<bool>,
+    on_failure: Option<String>,
+    open_issue_on_failure: Option<bool>,
+    check_run: Option<bool>,
+    commit_status: Option<bool>,
+}
+
+impl Settings {
+    /// Overrides the settings of the `config` with the ones given here.
+    fn apply(&self, config: &mut Config) -> Result<()> {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        fn set_some<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+        fn set_list(target: &mut String, value: &Option<Vec<String>>) {
+            if let Some(value) = value {
+                *target = value.join(",");
+            }
+        }
+
+        set_some(&mut config.base_repo, &self.base_repository);
+        set_some(&mut config.head_repo, &self.head_repository);
+        set_some(&mut config.base_env_prefix, &self.base_env_prefix);
+        set_some(&mut config.head_env_prefix, &self.head_env_prefix);
+        if let Some(filter_tags) = &self.filter_tags {
+            config.filter_tags = filter_tags.parse()?;
+        }
+        if let Some(list_tags_api) = &self.list_tags_api {
+            config.list_tags_api = list_tags_api.parse()?;
+        }
+        if let Some(branch_template) = &self.branch_template {
+            config.branch_template = branch_template.parse()?;
+        }
+        set_some(&mut config.patch_url, &self.apply_patch);
+        set_some(&mut config.patch_message, &self.patch_message);
+        set(&mut config.patch_author, &self.patch_author);
+        set(&mut config.patch_author_email, &self.patch_author_email);
+        set(&mut config.patch_committer, &self.patch_committer);
+        set(
+            &mut config.patch_committer_email,
+            &self.patch_committer_email,
+        );
+        set_some(&mut config.pull_request_base, &self.pull_request_base);
+        set_some(&mut config.pull_request_title, &self.pull_request_title);
+        set_some(&mut config.pull_request_body, &self.pull_request_body);
+        set_list(&mut config.pull_request_labels, &self.pull_request_labels);
+        set_list(
+            &mut config.pull_request_assignees,
+            &self.pull_request_assignees,
+        );
+        set_list(
+            &mut config.pull_request_reviewers,
+            &self.pull_request_reviewers,
+        );
+        set(&mut config.create_release, &self.create_release);
+        if let Some(on_failure) = &self.on_failure {
+            config.on_failure = on_failure.parse()?;
+        }
+        set(
+            &mut config.open_issue_on_failure,
+            &self.open_issue_on_failure,
+        );
+        set(&mut config.check_run, &self.check_run);
+        set(&mut config.commit_status, &self.commit_status);
+        Ok(())
+    }
+}
+
+/// Returns the settings of each pair of repositories to sync, which are
+/// defined by the configuration file of the `config` if any, or is the single
+/// pair given by the `config` itself.
+pub fn pairs(config: &Config) -> Result<Vec<Config>> {
+    let path = match &config.config_file {
+        Some(path) if !path.as_os_str().is_empty() => path,
+        _ => return Ok(vec![config.clone()]),
+    };
+    let content = fs::read_to_string(path)
+        .context(format!("Failed to read config file '{}'", path.display()))?;
+    let file = parse(path, &content)
+        .context(format!("Failed to parse config file '{}'", path.display()))?;
+
+    let mut names = HashSet::new();
+    let mut pairs = Vec::new();
+    for settings in &file.pairs {
+        let name = match &settings.name {
+            Some(name) => name,
+            None => bail!("Every pair of '{}' must have a name", path.display()),
+        };
+        if !name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || "._-".contains(char))
+        {
+            bail!("Name of pair '{name}' must only contain letters, digits, '.', '_' or '-'");
+        }
+        if !names.insert(name) {
+            bail!("Pair '{name}' is defined more than once");
+        }
+
+        let mut pair = config.clone();
+        file.defaults.apply(&mut pair)?;
+        settings
+            .apply(&mut pair)
+            .context(format!("Invalid settings of pair '{name}'"))?;
+        // Each pair is cloned into its own directory
+        pair.cloned_path = pair.cloned_path.join(name);
+        pair.pair = Some(name.clone());
+        pairs.push(pair);
+    }
+    if pairs.is_empty() {
+        bail!("No pair of repositories is defined in '{}'", path.display());
+    }
+    Ok(pairs)
+}
+
+fn parse(path: &Path, content: &str) -> Result<ConfigFile> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => Ok(toml::from_str(content)?),
+        Some("yml" | "yaml") => Ok(serde_yaml::from_str(content)?),
+        _ => bail!("The config file must be a TOML or YAML file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{cli::Cli, test_fn};
+
+    fn parse_pairs(file_name: &str, content: &str) -> Result<Vec<Config>> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join(file_name);
+        fs::write(&path, content)?;
+        let cli = Cli::try_parse_from([
+            "tags-sync".as_ref(),
+            "run".as_ref(),
+            "--config".as_ref(),
+            path.as_os_str(),
+            "--cloned-path".as_ref(),
+            "head-repo".as_ref(),
+            "--create-release".as_ref(),
+        ])?;
+        pairs(cli.command.config())
+    }
+
+    test_fn!(parse_toml_pairs {
+        let pairs = parse_pairs("tags-sync.toml", r#"
+            [defaults]
+            filter-tags = "^v"
+            pull-request-labels = ["upstream", "sync"]
+
+            [[pairs]]
+            name = "linux"
+            base-repository = "torvalds/linux"
+            head-repository = "me/linux"
+
+            [[pairs]]
+            name = "git"
+            base-repository = "https://git.kernel.org/pub/scm/git/git.git"
+            head-repository = "gitlab:me/git"
+            head-env-prefix = "WORK"
+            branch-template = "upstream/{tag}"
+            create-release = false
+        "#)?;
+
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].pair.as_deref(), Some("linux"));
+        assert_eq!(pairs[0].base_repo.as_deref(), Some("torvalds/linux"));
+        assert_eq!(pairs[0].cloned_path, Path::new("head-repo/linux"));
+        assert_eq!(pairs[0].pull_request_labels, "upstream,sync");
+        assert!(pairs[0].create_release);
+        assert_eq!(pairs[0].head_env_prefix, None);
+        assert_eq!(pairs[1].head_env_prefix.as_deref(), Some("WORK"));
+        assert_eq!(pairs[1].base_env_prefix, None);
+        env::set_var("WORK_GITLAB_URL", "https://gitlab.example.com");
+        let host = pairs[1].head_host();
+        assert_eq!(host.gitlab_url.as_deref(), Some("https://gitlab.example.com"));
+        assert!(pairs[1].filter_tags.is_match("v1.0"));
+        assert!(!pairs[1].filter_tags.is_match("1.0"));
+        assert_eq!(pairs[1].branch_template.branch("v1.0"), "upstream/v1.0");
+        assert!(!pairs[1].create_release);
+    });
+
+    test_fn!(parse_yaml_pairs {
+        let pairs = parse_pairs("tags-sync.yml", "
+            pairs:
+              - name: linux
+                base-repository: torvalds/linux
+                head-repository: me/linux
+                check-run: true
+        ")?;
+        assert_eq!(pairs.len(), 1);
+        assert!(pairs[0].check_run);
+    });
+
+    test_fn!(reject_invalid_pairs {
+        let pair = "base-repository = 'a/b'\nhead-repository = 'c/d'";
+        let error = parse_pairs("a.toml", &format!("[[pairs]]\n{pair}"))
+            .unwrap_err();
+        assert!(error.to_string().contains("must have a name"));
+        let error = parse_pairs("a.toml", &format!(
+            "[[pairs]]\nname = 'a'\n{pair}\n[[pairs]]\nname = 'a'\n{pair}"
+        ))
+        .unwrap_err();
+        assert!(error.to_string().contains("defined more than once"));
+        let error = parse_pairs("a.toml", &format!("[[pairs]]\nname = 'a'\nfilter = 'v'\n{pair}"))
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("unknown field `filter`"));
+    });
+}
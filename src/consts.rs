@@ -1,3 +1,5 @@
 pub const SYNC_PREFIX: &str = "sync-";
 pub const UPSTREAM: &str = "upstream";
 pub const ORIGIN: &str = "origin";
+pub const API_CACHE_DIR: &str = ".tags-sync-cache";
+pub const LOCK_REPO_DIR: &str = ".tags-sync-lock";
@@ -1,74 +1,279 @@
 use std::{
     fmt,
     fmt::{Debug, Formatter},
-    path::{Path, PathBuf},
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context as ResultContext, Result};
-use git2::{BranchType, Diff, Repository, Signature};
-use log::debug;
-use octocrab::{repos::RepoHandler, Octocrab};
+use anyhow::{bail, Context as ResultContext, Error, Result};
+use git2::{BranchType, Repository, Signature};
+use log::{debug, info};
+use octocrab::Octocrab;
 use regex::Regex;
 use reqwest::Url;
+use strum::EnumString;
 
 use crate::{
+    cli::{non_empty, Config},
     consts::*,
-    get_env,
-    utils::{github_api, CommitInfo, RepoExt, TagsExt},
+    utils::{
+        github_api, lock_run_id, refresh_installation_token, retry, retry_async, Action,
+        BranchTemplate, CheckRun, CommitInfo, DetectReport, Failure, FailurePolicy, Forge,
+        GitHubApp, GitHubGraphQlRepo, GitHubHost, GitHubRepo, GitLabHost, GitLabRepo, GiteaHost,
+        GiteaRepo, HostSettings, Lock, PatchFile, PatchStatus, PlainGitRepo, PullRequestTemplate,
+        RepoExt, RepoSpec, ResponseCache, StatusReport, SyncError, SyncReport, SyncStep, Tag,
+        TagOutcome, TagReport,
+    },
     RepoHandlerExt,
 };
 
-/// Global context of the project.
-pub struct Context {
-    /// Owner of the base repository.
-    base_repo_owner: String,
-    /// Name of the base repository.
-    base_repo_name: String,
+/// API used to list the tags of the base repository.
+#[derive(Debug, Clone, Copy, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum TagsApi {
+    /// The REST API, which only returns the name and commit SHA of each tag.
+    Rest,
+    /// The GraphQL API, which also returns the commit date and annotation of
+    /// each tag.
+    GraphQl,
+}
+
+/// A repository together with the API client of its forge.
+enum RepoClient {
+    GitHub {
+        owner: String,
+        name: String,
+        host: GitHubHost,
+        api: Octocrab,
+        /// ID of the GitHub App installation the `api` is authenticated as,
+        /// if any.
+        installation: Option<u64>,
+    },
+    GitLab(GitLabRepo),
+    Gitea(GiteaRepo),
+    Git(PlainGitRepo),
+}
+
+impl RepoClient {
+    /// Creates the client of the repository specified by `spec`, whose
+    /// instance is configured by the `host` settings.
+    ///
+    /// If a GitHub `app` is given, we authenticate as its installation on the
+    /// repository. If the repository is on the same GitHub instance as the
+    /// `shared` one, we reuse its API client instead.
+    async fn new(
+        spec: RepoSpec,
+        host: &HostSettings,
+        app: Option<&GitHubApp>,
+        shared: Option<&RepoClient>,
+    ) -> Result<Self> {
+        Ok(match spec {
+            RepoSpec::GitHub { owner, name } => {
+                let host = GitHubHost::new(host)?;
+                let (api, installation) = match shared {
+                    Some(Self::GitHub {
+                        host: shared_host,
+                        api,
+                        installation,
+                        ..
+                    }) if *shared_host == host => (api.clone(), *installation),
+                    _ => match app {
+                        Some(app) => {
+                            let (api, installation_id) =
+                                app.installation_api(&host, &owner, &name).await?;
+                            (api, Some(installation_id))
+                        }
+                        None => (github_api(&host, &owner, &name, None).await?, None),
+                    },
+                };
+                Self::GitHub {
+                    owner,
+                    name,
+                    host,
+                    api,
+                    installation,
+                }
+            }
+            RepoSpec::GitLab { path } => {
+                Self::GitLab(GitLabRepo::new(GitLabHost::new(host)?, path))
+            }
+            RepoSpec::Gitea { owner, name } => {
+                Self::Gitea(GiteaRepo::lookup(GiteaHost::new(host)?, owner, name).await?)
+            }
+            RepoSpec::Git { url } => Self::Git(PlainGitRepo::new(url).with_credentials(host)),
+        })
+    }
 
-    /// Owner of the head repository.
-    head_repo_owner: String,
-    /// Name of the head repository.
-    head_repo_name: String,
+    /// Returns the repository if it is on GitHub.
+    fn github_repo(&self, cache: &ResponseCache) -> Option<GitHubRepo<'_>> {
+        match self {
+            Self::GitHub {
+                owner,
+                name,
+                host,
+                api,
+                installation,
+            } => Some(
+                GitHubRepo::new(api, owner.clone(), name.clone())
+                    .with_host(host.clone(), *installation)
+                    .with_cache(cache.clone()),
+            ),
+            Self::GitLab(_) | Self::Gitea(_) | Self::Git(_) => None,
+        }
+    }
+
+    fn forge(&self, cache: &ResponseCache) -> Box<dyn Forge + Send + Sync + '_> {
+        match self {
+            Self::GitHub { .. } => Box::new(self.github_repo(cache).unwrap()),
+            Self::GitLab(repo) => Box::new(repo.clone()),
+            Self::Gitea(repo) => Box::new(repo.clone()),
+            Self::Git(repo) => Box::new(repo.clone()),
+        }
+    }
+}
+
+impl Debug for RepoClient {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::GitHub {
+                owner, name, host, ..
+            } => fmt
+                .debug_struct("GitHub")
+                .field("repo", &format!("{owner}/{name}"))
+                .field("host", host)
+                .finish(),
+            Self::GitLab(repo) => fmt.debug_tuple("GitLab").field(repo).finish(),
+            Self::Gitea(repo) => fmt.debug_tuple("Gitea").field(repo).finish(),
+            Self::Git(repo) => fmt.debug_tuple("Git").field(repo).finish(),
+        }
+    }
+}
+
+/// Global context of the project, for a single pair of repositories.
+pub struct Context {
+    /// Name of the pair in the configuration file, if any.
+    pair: Option<String>,
+    /// The base repository, whose tags are synced.
+    base_repo: RepoClient,
+    /// The head repository, where the tags are synced to as branches.
+    head_repo: RepoClient,
 
     /// Local clone path for the head repository.
     clone_path: PathBuf,
+    /// Path of the empty repository which locks the head repository when it
+    /// is not cloned.
+    lock_repo_path: PathBuf,
+    /// How long the lease on the head repository lasts before being
+    /// considered stale.
+    lock_timeout: Duration,
 
     /// Filter tags by regular expression.
     filter_tags: Regex,
+    /// API used to list the tags of the base repository.
+    tags_api: TagsApi,
+    /// Template of the name of the branch synced from each tag.
+    branch_template: BranchTemplate,
     /// URL of patch file to apply to the head repository.
     patch_file_url: Option<Url>,
-    /// GitHub API client.
-    github_api: Octocrab,
+    /// Names and emails of the author and committer of the applied patch.
+    patch_author: (String, String),
+    patch_committer: (String, String),
+    /// Commit message of the applied patch, if not the default one.
+    patch_message: Option<String>,
+    /// Template of the pull request opened for each synced branch, if any.
+    pull_request: Option<PullRequestTemplate>,
+    /// Whether to create a release for each synced tag.
+    create_release: bool,
+    /// How the failures of the tags affect the sync.
+    failure_policy: FailurePolicy,
+    /// Whether to open an issue in the head repository for each tag that
+    /// fails to sync.
+    failure_issues: bool,
+    /// Whether to publish a check run with the outcome of each tag to the
+    /// head repository.
+    check_run: bool,
+    /// Whether to set a commit status on each synced branch, telling how the
+    /// patch was applied.
+    commit_status: bool,
+    /// Whether to only sync the tags in the local clone, without pushing
+    /// anything to the head repository.
+    dry_run: bool,
+    /// Cache of the GitHub API responses.
+    api_cache: ResponseCache,
 }
 
 impl Context {
-    pub fn new() -> Result<Self> {
-        fn parse_repo(value: String) -> Result<(String, String)> {
-            let repo = value.split('/').collect::<Vec<_>>();
-            if repo.len() != 2 {
-                bail!("'{}' must be in format 'owner/repo'.", value);
+    pub async fn new(config: &Config) -> Result<Self> {
+        // The GitHub App lives on the instance of the head repository, and the
+        // base repository shares the same client if it is on that instance too
+        let app = match (
+            non_empty(&config.app_id),
+            non_empty(&config.app_private_key),
+        ) {
+            (Some(id), Some(key)) => {
+                let installation_id = non_empty(&config.app_installation_id)
+                    .map(|id| id.parse().context("The installation ID must be a number"))
+                    .transpose()?;
+                let id = id.parse().context("The GitHub App ID must be a number")?;
+                Some(GitHubApp::new(id, key, installation_id)?)
             }
-            Ok((
-                repo.first().unwrap().to_string(),
-                repo.last().unwrap().to_string(),
-            ))
-        }
-
-        let github_workspace = get_env!("GITHUB_WORKSPACE");
-        let github_workspace_path = Path::new(&github_workspace);
-
-        let (base_repo_owner, base_repo_name) = parse_repo(get_env!("BASE_REPO"))?;
-        let (head_repo_owner, head_repo_name) = parse_repo(get_env!("HEAD_REPO"))?;
-
+            _ => None,
+        };
+        let head_spec = non_empty(&config.head_repo)
+            .context("The head repository is not set")?
+            .parse()?;
+        let base_spec = non_empty(&config.base_repo)
+            .context("The base repository is not set")?
+            .parse()?;
+        let head_repo = RepoClient::new(head_spec, &config.head_host(), app.as_ref(), None).await?;
+        let base_repo =
+            RepoClient::new(base_spec, &config.base_host(), None, Some(&head_repo)).await?;
+
+        let pull_request = non_empty(&config.pull_request_base).map(|base| {
+            PullRequestTemplate::new(
+                base.to_string(),
+                non_empty(&config.pull_request_title).map(str::to_string),
+                non_empty(&config.pull_request_body).map(str::to_string),
+                &config.pull_request_labels,
+                &config.pull_request_assignees,
+                &config.pull_request_reviewers,
+            )
+        });
         let result = Self {
-            base_repo_owner,
-            head_repo_owner,
-            base_repo_name,
-            head_repo_name,
-            github_api: github_api()?,
-            filter_tags: Regex::new(&get_env!("FILTER_TAGS"))?,
-            patch_file_url: Url::parse(&get_env!("PATCH_URL")).ok(),
-            clone_path: github_workspace_path.join(&get_env!("CLONED_PATH")),
+            pair: config.pair.clone(),
+            base_repo,
+            head_repo,
+            api_cache: ResponseCache::new(config.workspace.join(API_CACHE_DIR)),
+            filter_tags: config.filter_tags.clone(),
+            tags_api: config.list_tags_api,
+            branch_template: config.branch_template.clone(),
+            patch_file_url: non_empty(&config.patch_url)
+                .map(Url::parse)
+                .transpose()
+                .context("The patch URL is invalid")?,
+            patch_author: (
+                config.patch_author.clone(),
+                config.patch_author_email.clone(),
+            ),
+            patch_committer: (
+                config.patch_committer.clone(),
+                config.patch_committer_email.clone(),
+            ),
+            patch_message: non_empty(&config.patch_message).map(str::to_string),
+            pull_request,
+            create_release: config.create_release,
+            failure_policy: config.on_failure,
+            failure_issues: config.open_issue_on_failure,
+            check_run: config.check_run,
+            commit_status: config.commit_status,
+            dry_run: config.dry_run,
+            clone_path: config.workspace.join(&config.cloned_path),
+            lock_repo_path: config
+                .workspace
+                .join(LOCK_REPO_DIR)
+                .join(config.pair.as_deref().unwrap_or("head-repo")),
+            lock_timeout: Duration::from_secs(config.lock_timeout),
         };
 
         debug!("Load configuration {:#?}", &result);
@@ -76,8 +281,14 @@ impl Context {
         Ok(result)
     }
 
-    pub fn github_workspace(&self) -> &Path {
-        self.clone_path.parent().unwrap()
+    /// Returns the name of the pair of repositories in the configuration
+    /// file, if any.
+    pub fn pair(&self) -> Option<&str> {
+        self.pair.as_deref()
+    }
+
+    pub fn branch_template(&self) -> &BranchTemplate {
+        &self.branch_template
     }
 
     /// Returns the new tags in the base repository.
@@ -87,98 +298,527 @@ impl Context {
     /// The result is derived by checking whether the **head repository**
     /// contains the corresponding branch of the tag of the **base repository**.
     ///
-    /// A corresponding branch name of a tag is in "sync-${tag_name}" format.
-    /// For example, the corresponding branch of the "v1.0" tag is "sync-v1.0".
-    pub async fn new_tags(&self) -> Result<Vec<String>> {
+    /// A corresponding branch name of a tag is given by the branch template,
+    /// which is "sync-{tag}" by default. For example, the corresponding branch
+    /// of the "v1.0" tag is "sync-v1.0".
+    pub async fn new_tags(&self) -> Result<DetectReport> {
+        let started = Instant::now();
         let mut new_tags = Vec::new();
-        let base_tags = self.base_repo().list_all_tags().await?;
-        let head_branches = self.head_repo().list_all_branches().await?;
-        // https://github.com/rust-lang/rust-clippy/issues/6909
-        #[allow(clippy::needless_collect)]
-        let head_branch_names = head_branches
-            .into_iter()
-            .map(|branch| branch.name)
-            .collect::<Vec<_>>();
+        let (base_tags, head_branch_names) = self.tags_and_branches().await?;
 
         // Add all filtered tags that we think are new
+        let base_tag_count = base_tags.len();
         for tag in base_tags {
-            let branch_name = format!("{SYNC_PREFIX}{}", tag.name);
+            let branch_name = self.branch_template.branch(&tag.name);
             if !head_branch_names.contains(&branch_name) && self.filter_tags.is_match(&tag.name) {
                 new_tags.push(tag);
             }
         }
 
-        Ok(new_tags.names())
+        Ok(DetectReport {
+            pair: self.pair.clone(),
+            base_tags: base_tag_count,
+            new_tags,
+            duration: started.elapsed(),
+        })
+    }
+
+    /// Compares the filtered tags of the base repository against the synced
+    /// branches of the head repository.
+    pub async fn status(&self) -> Result<StatusReport> {
+        let (base_tags, head_branch_names) = self.tags_and_branches().await?;
+        let mut report = StatusReport::default();
+        for tag in base_tags
+            .iter()
+            .filter(|tag| self.filter_tags.is_match(&tag.name))
+        {
+            let branch_name = self.branch_template.branch(&tag.name);
+            match head_branch_names.contains(&branch_name) {
+                true => report.synced.push(tag.name.clone()),
+                false => report.pending.push(tag.name.clone()),
+            }
+        }
+        // Whether the tag matches the filter does not matter here, so that
+        // changing the filter does not turn the synced branches stale
+        report.stale = head_branch_names
+            .into_iter()
+            .filter(|branch| match self.branch_template.tag(branch) {
+                Some(tag) => base_tags.iter().all(|base_tag| base_tag.name != tag),
+                None => false,
+            })
+            .collect();
+        Ok(report)
+    }
+
+    /// Deletes the synced branches of the head repository whose tag no longer
+    /// exists in the base repository, and returns their names.
+    ///
+    /// The head repository is locked while deleting, like while syncing. In a
+    /// dry run, the branches are only returned.
+    pub async fn prune(&self) -> Result<Vec<String>> {
+        if self.dry_run {
+            return Ok(self.status().await?.stale);
+        }
+        // A sync running meanwhile may push the branches found stale
+        let lock_repo = self.open_lock_repo()?;
+        let lock = self.lock_head_repo(&lock_repo)?;
+        let result = self.delete_stale_branches().await;
+        self.unlock_head_repo(&lock_repo, lock).await;
+        result
+    }
+
+    /// Deletes the synced branches whose tag no longer exists in the base
+    /// repository, and returns their names.
+    async fn delete_stale_branches(&self) -> Result<Vec<String>> {
+        let stale = self.status().await?.stale;
+        let head_forge = self.head_forge();
+        for branch in &stale {
+            head_forge
+                .delete_branch(branch)
+                .await
+                .context(format!("Failed to delete branch: {branch}"))?;
+            info!("Deleted branch: {branch}");
+        }
+        Ok(stale)
+    }
+
+    /// Lists the tags of the base repository, and the names of the branches
+    /// of the head repository.
+    async fn tags_and_branches(&self) -> Result<(Vec<Tag>, Vec<String>)> {
+        let base_tags = self.base_tags_repo().list_all_tags().await?;
+        let head_branches = self.head_forge().list_all_branches().await?;
+        let head_branch_names = head_branches
+            .into_iter()
+            .map(|branch| branch.name)
+            .collect();
+        Ok((base_tags, head_branch_names))
     }
 
     /// Sync [`new_tags`] from the base repository to the head repository as
     /// branches.
-    pub async fn sync_tags(&self, new_tags: &[&str]) -> Result<()> {
-        // Download the patch file to prepare for subsequent work
-        let diff = None::<Diff>;
+    ///
+    /// A failure does not return an error, but is recorded in the returned
+    /// report, together with the failing step. Whether a failing tag stops
+    /// the sync depends on the [`FailurePolicy`].
+    ///
+    /// In a dry run, the branches are only created and patched in the local
+    /// clone, and nothing is reported to the head repository.
+    pub async fn sync_tags(&self, new_tags: &[Tag]) -> SyncReport {
+        let started = Instant::now();
+        let mut report = SyncReport {
+            pair: self.pair.clone(),
+            dry_run: self.dry_run,
+            policy: self.failure_policy,
+            ..Default::default()
+        };
+        let (cloned_repo, patch_file, lock) = match self.prepare_sync(new_tags).await {
+            Ok(prepared) => prepared,
+            Err(failure) => {
+                report.failures.push(failure);
+                report.duration = started.elapsed();
+                return report;
+            }
+        };
+
+        // Checkout all the new tags as branches, reporting the outcome of
+        // each one as we go, until any of them fails if the sync fails fast
+        let mut check_run = self.start_check_run().await;
+        for Tag { name: tag, sha, .. } in new_tags {
+            Action::group(&format!("Sync tag {tag}"));
+            let mut tag_report = TagReport::new(tag, self.branch_template.branch(tag));
+            let skip =
+                self.failure_policy == FailurePolicy::FailFast && !report.failures.is_empty();
+            if !skip {
+                let started = Instant::now();
+                let result = self
+                    .sync_tag(&cloned_repo, patch_file.as_ref(), sha, &mut tag_report)
+                    .await;
+                tag_report.duration = started.elapsed();
+                tag_report.outcome = match result {
+                    Ok(patch) => TagOutcome::Synced { patch },
+                    Err(error) => {
+                        let outcome = failed_outcome(tag_report.step, &error);
+                        report.failures.push(Failure {
+                            step: tag_report.step,
+                            tag: Some(tag.to_string()),
+                            error,
+                        });
+                        outcome
+                    }
+                };
+            }
+            if self.failure_issues && !self.dry_run {
+                self.report_sync(&tag_report).await;
+            }
+            if let Some(check_run) = &mut check_run {
+                if let Err(err) = check_run.report(&tag_report).await {
+                    Action::warning(
+                        &SyncStep::Hook.title(Some(tag)),
+                        &format!("Failed to report the tag to the check run: {err:#}"),
+                    );
+                }
+            }
+            report.tags.push(tag_report);
+            Action::end_group();
+        }
+        if let Some(check_run) = check_run {
+            if let Err(err) = check_run.finish().await {
+                Action::warning(
+                    &SyncStep::Hook.title(None),
+                    &format!("Failed to complete the check run: {err:#}"),
+                );
+            }
+        }
+        self.unlock_head_repo(&cloned_repo, lock).await;
+
+        report.duration = started.elapsed();
+        report
+    }
+
+    /// Downloads the patch, clones the head repository and locks it against
+    /// concurrent runs, then fetches all the [`new_tags`] from the base
+    /// repository.
+    ///
+    /// Once locked, the head repository must be unlocked by
+    /// [`Self::unlock_head_repo`].
+    async fn prepare_sync(
+        &self,
+        new_tags: &[Tag],
+    ) -> Result<(Repository, Option<PatchFile>, Option<Lock>), Failure> {
+        let mut patch_file = None;
         if let Some(patch_file_url) = &self.patch_file_url {
-            let response = reqwest::get(patch_file_url.clone()).await?;
-            let patch = response.bytes().await?;
-            Diff::from_buffer(&patch)?;
+            let patch = retry_async("Download patch", || async {
+                let response = reqwest::get(patch_file_url.clone()).await?;
+                Ok(response.error_for_status()?.bytes().await?)
+            })
+            .await
+            .map_err(SyncError::request)
+            .map_err(Failure::at(SyncStep::Patch))?;
+            patch_file = Some(PatchFile::parse(&patch).map_err(Failure::at(SyncStep::Patch))?);
         }
 
-        let cloned_repo = self.clone_repo().await?;
-        // Make sure all tags are fetched from upstream
-        cloned_repo.fetch_upstream_tags(new_tags)?;
-        debug!(
-            "Branches: {}",
-            cloned_repo
-                .branches(Some(BranchType::Local))?
-                .flatten()
-                .map(|(branch, _)| branch.name().unwrap().unwrap().to_string())
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
+        let cloned_repo = self
+            .clone_repo()
+            .await
+            .map_err(Failure::at(SyncStep::Clone))?;
+        // Lock before looking at the branches of the head repository, so that
+        // a concurrent run cannot push them in the meantime
+        let lock = self
+            .lock_head_repo(&cloned_repo)
+            .map_err(Failure::at(SyncStep::Lock))?;
+        if let Err(failure) = self.fetch_tags_and_branches(&cloned_repo, new_tags) {
+            self.unlock_head_repo(&cloned_repo, lock).await;
+            return Err(failure);
+        }
 
-        // Checkout all the new tags as branches
-        for tag in new_tags {
-            cloned_repo
-                .checkout_tag(tag)
-                .context(format!("Failed to checkout tag: {tag}"))?;
-
-            // Once the branch is synced, we can apply the patch
-            // to complete any needed changes
-            if let Some(diff) = &diff {
-                cloned_repo
-                    .apply_patch(diff, self.commit_info()?)
-                    .context(format!("Failed to apply patch to {SYNC_PREFIX}{tag}"))?;
-            }
+        Ok((cloned_repo, patch_file, lock))
+    }
 
-            // Push all changes to the remote
-            cloned_repo
-                .push_head()
-                .context(format!("Failed to push branch: {SYNC_PREFIX}{tag}"))?;
+    /// Fetches the [`new_tags`] from the base repository, and the branches of
+    /// the head repository.
+    fn fetch_tags_and_branches(
+        &self,
+        cloned_repo: &Repository,
+        new_tags: &[Tag],
+    ) -> Result<(), Failure> {
+        // Make sure all tags are fetched from upstream, with the credentials
+        // of the base repository in case it is private
+        let base_credentials = self
+            .base_forge()
+            .credentials()
+            .map_err(Failure::at(SyncStep::Fetch))?;
+        let names = new_tags
+            .iter()
+            .map(|tag| tag.name.as_str())
+            .collect::<Vec<_>>();
+        cloned_repo
+            .fetch_upstream_tags(&names, base_credentials.as_ref())
+            .map_err(Failure::at(SyncStep::Fetch))?;
+        // Also fetch the branches of the head repository, to find the ones
+        // already pushed by an interrupted run
+        let head_credentials = self
+            .head_forge()
+            .credentials()
+            .map_err(Failure::at(SyncStep::Fetch))?;
+        cloned_repo
+            .fetch_origin_branches(head_credentials.as_ref())
+            .map_err(Failure::at(SyncStep::Fetch))?;
+        if let Ok(branches) = cloned_repo.branches(Some(BranchType::Local)) {
+            debug!(
+                "Branches: {}",
+                branches
+                    .flatten()
+                    .filter_map(|(branch, _)| Some(branch.name().ok()??.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
         }
 
         Ok(())
     }
 
-    async fn clone_repo(&self) -> Result<Repository> {
-        // Clone only if the cache does not exist, otherwise we just open
-        let repo = if !self.clone_path.exists() {
-            macro_rules! clone_url {
-                ($name:ident) => {
-                    paste::paste! {
-                        self.[<$name _repo>]().get().await?.clone_url.context(format!(
-                            "Failed to get clone URL for {} repository.",
-                            stringify!($name)
-                        ))?
+    /// Locks the head repository against concurrent runs, unless in a dry run
+    /// which pushes nothing.
+    fn lock_head_repo(&self, cloned_repo: &Repository) -> Result<Option<Lock>> {
+        if self.dry_run {
+            return Ok(None);
+        }
+        let credentials = self.head_forge().credentials()?;
+        let lock = Lock::acquire(
+            cloned_repo,
+            credentials.as_ref(),
+            &lock_run_id(),
+            self.lock_timeout,
+        )?;
+        Ok(Some(lock))
+    }
+
+    /// Opens the clone of the head repository if it exists, or else an empty
+    /// repository whose origin is the head repository, which is enough to
+    /// lock it without cloning it.
+    fn open_lock_repo(&self) -> Result<Repository> {
+        if self.clone_path.exists() {
+            return Ok(Repository::open(&self.clone_path)?);
+        }
+        let repo = Repository::init_bare(&self.lock_repo_path)?;
+        if repo.find_remote(ORIGIN).is_err() {
+            repo.remote(ORIGIN, self.head_forge().clone_url()?.as_str())?;
+        }
+        Ok(repo)
+    }
+
+    /// Unlocks the head repository if it was locked.
+    ///
+    /// Failing to unlock it is only logged, as the lock expires anyway.
+    async fn unlock_head_repo(&self, cloned_repo: &Repository, lock: Option<Lock>) {
+        let lock = match lock {
+            Some(lock) => lock,
+            None => return,
+        };
+        let result = async {
+            self.refresh_head_token().await?;
+            let credentials = self.head_forge().credentials()?;
+            lock.release(cloned_repo, credentials.as_ref())?;
+            Ok::<_, Error>(())
+        };
+        if let Err(err) = result.await {
+            Action::warning(
+                &SyncStep::Lock.title(None),
+                &format!("Failed to unlock the head repository: {err:#}"),
+            );
+        }
+    }
+
+    /// Syncs a single tag to its branch of the head repository, applying the
+    /// [`patch_file`] if any, and returns how the patch was applied.
+    ///
+    /// The tag must still point to the commit [`sha`] it was detected at.
+    ///
+    /// The links to what is created for the tag are added to its [`report`],
+    /// together with the step being run.
+    async fn sync_tag(
+        &self,
+        cloned_repo: &Repository,
+        patch_file: Option<&PatchFile>,
+        sha: &str,
+        report: &mut TagReport,
+    ) -> Result<Option<PatchStatus>> {
+        let (tag, branch) = (report.tag.clone(), report.branch.clone());
+        let tag = tag.as_str();
+        report.step = SyncStep::Checkout;
+        cloned_repo
+            .checkout_tag(tag, &branch)
+            .context(format!("Failed to checkout tag: {tag}"))?;
+        let upstream_sha = cloned_repo.head()?.peel_to_commit()?.id().to_string();
+        if upstream_sha != sha {
+            bail!("Tag {tag} was detected at {sha}, but now points to {upstream_sha}");
+        }
+        report.upstream_sha = Some(upstream_sha);
+
+        // Once the branch is synced, we can apply the patch
+        // to complete any needed changes
+        report.step = SyncStep::Patch;
+        let (status, patch) = match (patch_file, &self.patch_file_url) {
+            (Some(patch_file), Some(patch_file_url)) => {
+                let status = cloned_repo
+                    .apply_patch(patch_file, self.commit_info()?)
+                    .context(format!("Failed to apply patch to {branch}"))?;
+                let patch = match status {
+                    PatchStatus::Clean => format!("applied {patch_file_url}"),
+                    PatchStatus::ThreeWay => {
+                        format!("applied {patch_file_url} with a three-way merge")
                     }
                 };
+                (Some(status), patch)
+            }
+            _ => (None, "none".to_string()),
+        };
+
+        // A branch pushed by an interrupted run is kept if it has the same
+        // content, and only what follows the push is done again
+        report.step = SyncStep::Push;
+        report.resumed = cloned_repo.reuse_origin_branch(&branch)?.is_some();
+        let sha = cloned_repo.head()?.peel_to_commit()?.id().to_string();
+        if report.resumed {
+            info!("Branch {branch} was already pushed at {sha}, not pushing it again");
+        }
+
+        // The head of the local branch is what would have been pushed
+        if self.dry_run {
+            if !report.resumed {
+                info!("Dry run, not pushing branch {branch} at {sha}");
+            }
+            report.head_sha = Some(sha);
+            return Ok(status);
+        }
+
+        // Push all changes to the remote, with a fresh token in case
+        // the installation token of the GitHub App is about to expire
+        let head_forge = self.head_forge();
+        if !report.resumed {
+            self.refresh_head_token().await?;
+            cloned_repo
+                .push_head(head_forge.credentials()?.as_ref())
+                .context(format!("Failed to push branch: {branch}"))?;
+        }
+        report.branch_url = head_forge.branch_url(&branch);
+        report.head_sha = Some(sha.clone());
+
+        report.step = SyncStep::Hook;
+
+        if self.commit_status {
+            head_forge
+                .set_commit_status(&sha, status, Action::run_url().as_deref())
+                .await
+                .context(format!("Failed to set commit status of branch: {branch}"))?;
+            report.commit_status = true;
+        }
+        if let Some(template) = &self.pull_request {
+            let compare_url = self.base_forge().compare_url(&template.base, tag);
+            let request = template.render(tag, &branch, compare_url.as_ref(), &patch);
+            let url = head_forge
+                .open_pull_request(&request)
+                .await
+                .context(format!("Failed to open pull request for branch: {branch}"))?;
+            info!("Opened pull request: {url}");
+            report.compare_url = compare_url;
+            report.pull_request = Some(url);
+        }
+        if self.create_release {
+            let url = head_forge
+                .create_release(tag, &branch, tag, "")
+                .await
+                .context(format!("Failed to create release: {tag}"))?;
+            info!("Created release: {url}");
+            report.release = Some(url);
+        }
+
+        Ok(status)
+    }
+
+    /// Starts the check run on the head repository if enabled, which is only
+    /// available on GitHub.
+    ///
+    /// Failing to start it is only logged, so that the sync goes on.
+    async fn start_check_run(&self) -> Option<CheckRun<'_>> {
+        if !self.check_run || self.dry_run {
+            return None;
+        }
+        let title = SyncStep::Hook.title(None);
+        let repo = match self.head_repo.github_repo(&self.api_cache) {
+            Some(repo) => repo,
+            None => {
+                Action::warning(
+                    &title,
+                    "Check runs are only available when the head repository is on GitHub",
+                );
+                return None;
             }
+        };
+        CheckRun::start(&repo)
+            .await
+            .map_err(|err| {
+                Action::warning(&title, &format!("Failed to start the check run: {err:#}"))
+            })
+            .ok()
+    }
 
-            let head_url = clone_url!(head);
-            let base_url = clone_url!(base);
+    /// Opens an issue in the head repository if the [`tag`] failed to sync,
+    /// or comments on the one already open for it. Once the tag is synced,
+    /// its issue is closed.
+    ///
+    /// Failing to report is only logged, so that the sync error is not lost.
+    async fn report_sync(&self, report: &TagReport) {
+        let TagReport {
+            tag,
+            branch,
+            outcome,
+            ..
+        } = report;
+        if *outcome == TagOutcome::Skipped {
+            return;
+        }
+        let head_forge = self.head_forge();
+        let title = format!("Failed to sync tag {tag}");
+        let result = async {
+            let issue = head_forge.find_open_issue(&title).await?;
+            match (outcome, issue) {
+                (
+                    TagOutcome::Failed {
+                        error, conflicts, ..
+                    },
+                    Some(issue),
+                ) => {
+                    let body = failure_report(tag, branch, error, conflicts);
+                    head_forge.comment_issue(&issue, &body).await?;
+                    info!("Commented on issue: {}", issue.url);
+                }
+                (
+                    TagOutcome::Failed {
+                        error, conflicts, ..
+                    },
+                    None,
+                ) => {
+                    let body = failure_report(tag, branch, error, conflicts);
+                    let issue = head_forge.open_issue(&title, &body).await?;
+                    info!("Opened issue: {}", issue.url);
+                }
+                (TagOutcome::Synced { .. }, Some(issue)) => {
+                    let run = Action::run_url().unwrap_or_else(|| "a later run".to_string());
+                    let body = format!("Synced `{tag}` as `{branch}` in {run}.");
+                    head_forge.comment_issue(&issue, &body).await?;
+                    head_forge.close_issue(&issue).await?;
+                    info!("Closed issue: {}", issue.url);
+                }
+                _ => {}
+            }
+            Ok::<_, Error>(())
+        };
+        if let Err(err) = result.await {
+            Action::warning(
+                &SyncStep::Hook.title(Some(tag)),
+                &format!("Failed to report the sync to an issue: {err:#}"),
+            );
+        }
+    }
+
+    async fn clone_repo(&self) -> Result<Repository> {
+        // Clone only if the cache does not exist, otherwise we just open
+        let repo = if !self.clone_path.exists() {
+            let head_url = self.head_forge().clone_url()?;
+            let base_url = self.base_forge().clone_url()?;
 
             debug!("Git urls: head='{}', base='{}'", head_url, base_url);
 
-            let repo = Repository::clone(head_url.as_str(), &self.clone_path)
-                .context(format!("Failed to clone: '{head_url}'"))?;
+            let repo = retry("Clone head repository", || {
+                // Clean up the leftovers of the previous failed attempt
+                if self.clone_path.exists() {
+                    fs::remove_dir_all(&self.clone_path)?;
+                }
+                Ok(Repository::clone(head_url.as_str(), &self.clone_path)?)
+            })
+            .map_err(SyncError::git)
+            .context(format!("Failed to clone: '{head_url}'"))?;
             // Add upstream url to remote
             repo.remote(UPSTREAM, base_url.as_str())?;
 
@@ -192,27 +832,43 @@ impl Context {
         Ok(repo)
     }
 
-    fn base_repo(&self) -> RepoHandler {
-        self.github_api
-            .repos(self.base_repo_owner.clone(), self.base_repo_name.clone())
+    fn base_forge(&self) -> Box<dyn Forge + Send + Sync + '_> {
+        self.base_repo.forge(&self.api_cache)
+    }
+
+    /// Returns the base repository whose tags are listed through the
+    /// configured [`TagsApi`], which only applies to GitHub.
+    fn base_tags_repo(&self) -> Box<dyn RepoHandlerExt + Send + Sync + '_> {
+        match (self.tags_api, self.base_repo.github_repo(&self.api_cache)) {
+            (TagsApi::GraphQl, Some(repo)) => Box::new(GitHubGraphQlRepo::new(repo)),
+            _ => self.base_forge(),
+        }
     }
 
-    fn head_repo(&self) -> RepoHandler {
-        self.github_api
-            .repos(self.head_repo_owner.clone(), self.head_repo_name.clone())
+    fn head_forge(&self) -> Box<dyn Forge + Send + Sync + '_> {
+        self.head_repo.forge(&self.api_cache)
+    }
+
+    /// Refreshes the token of the GitHub App installation the head repository
+    /// is accessed as, if any, before it expires.
+    async fn refresh_head_token(&self) -> Result<()> {
+        match &self.head_repo {
+            RepoClient::GitHub {
+                installation: Some(installation_id),
+                ..
+            } => refresh_installation_token(*installation_id).await,
+            _ => Ok(()),
+        }
     }
 
     fn commit_info(&self) -> Result<CommitInfo> {
-        let author = Signature::now(&get_env!("PATCH_AUTHOR"), &get_env!("PATCH_AUTHOR_EMAIL"))?;
-        let committer = Signature::now(
-            &get_env!("PATCH_COMMITTER"),
-            &get_env!("PATCH_COMMITTER_EMAIL"),
-        )?;
-        let message = get_env!("PATCH_MESSAGE");
-        let message = if message.is_empty() {
-            format!("Apply patch from {}", self.patch_file_url.clone().unwrap())
-        } else {
-            message
+        let (name, email) = &self.patch_author;
+        let author = Signature::now(name, email)?;
+        let (name, email) = &self.patch_committer;
+        let committer = Signature::now(name, email)?;
+        let message = match &self.patch_message {
+            Some(message) => message.clone(),
+            None => format!("Apply patch from {}", self.patch_file_url.clone().unwrap()),
         };
         Ok((author, committer, message))
     }
@@ -221,28 +877,62 @@ impl Context {
 impl Debug for Context {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         fmt.debug_struct("Context")
-            .field(
-                "base_repo",
-                &format!("{}/{}", self.base_repo_owner, self.base_repo_name),
-            )
-            .field(
-                "head_repo",
-                &format!("{}/{}", self.head_repo_owner, self.head_repo_name),
-            )
+            .field("pair", &self.pair)
+            .field("base_repo", &self.base_repo)
+            .field("head_repo", &self.head_repo)
             .field("clone_path", &self.clone_path)
             .field("filter_tags", &self.filter_tags)
+            .field("tags_api", &self.tags_api)
+            .field("branch_template", &self.branch_template)
+            .field("pull_request", &self.pull_request)
+            .field("create_release", &self.create_release)
+            .field("api_cache", &self.api_cache)
             .finish()
     }
 }
 
+/// Returns the outcome of a tag that failed to sync at the [`step`] with the
+/// [`error`].
+fn failed_outcome(step: SyncStep, error: &Error) -> TagOutcome {
+    TagOutcome::Failed {
+        step,
+        error: format!("{error:#}"),
+        conflicts: error
+            .chain()
+            .find_map(|cause| match cause.downcast_ref::<SyncError>() {
+                Some(SyncError::PatchConflict(conflict)) => Some(conflict.files.clone()),
+                _ => None,
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// Describes why syncing the [`tag`] as the [`branch`] failed, for the issue
+/// opened about it.
+fn failure_report(tag: &str, branch: &str, error: &str, conflicts: &[String]) -> String {
+    let mut report =
+        format!("Failed to sync the upstream tag `{tag}` as `{branch}`:\n\n```\n{error}\n```\n");
+    if !conflicts.is_empty() {
+        report.push_str("\nThe patch conflicts with:\n\n");
+        for file in conflicts {
+            report.push_str(&format!("- `{file}`\n"));
+        }
+    }
+    if let Some(run) = Action::run_url() {
+        report.push_str(&format!("\nSee {run} for details.\n"));
+    }
+    report
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
 
+    use clap::Parser;
     use tempfile::tempdir;
 
     use super::*;
-    use crate::test_async_fn;
+    use crate::{cli::Cli, test_async_fn, test_fn, utils::TagsExt};
 
     macro_rules! test_with_context {
         ($name:ident($context:ident)$block:block) => {
@@ -254,21 +944,32 @@ mod tests {
                 env::set_var("CLONED_PATH", "rustlings-head");
                 env::set_var("FILTER_TAGS", ".*");
                 env::set_var("PATCH_URL", "https://github.com/rust-lang/rustlings/compare/main...ZhangHanDong:rustlings:main.patch");
-                env::set_var("SCRIPTS_AFTER_SYNC", "echo 'hello world'");
-                env::set_var("GITHUB_ACTOR", "chachako");
-                let $context = Context::new()?;
+                // The settings are taken from the environment, like in the action
+                let cli = Cli::try_parse_from(["tags-sync", "run"])?;
+                let $context = Context::new(cli.command.config()).await?;
                 $block
             });
         };
     }
 
+    test_fn!(report_failure {
+        let error = Error::msg("Connection reset").context("Failed to push branch: sync-v1.0");
+        let TagOutcome::Failed { error, conflicts, .. } = failed_outcome(SyncStep::Push, &error) else {
+            unreachable!()
+        };
+        let report = failure_report("v1.0", "sync-v1.0", &error, &conflicts);
+        assert!(report.starts_with("Failed to sync the upstream tag `v1.0` as `sync-v1.0`:"));
+        assert!(report.contains("Failed to push branch: sync-v1.0: Connection reset"));
+        assert!(!report.contains("conflicts"));
+    });
+
     test_with_context!(new_tags(context) {
         // We know that the head repository does not have any branch corresponding to
         // the tag of the base repository, so all the tags of the base repository are
         // new.
         assert_eq!(
-            context.new_tags().await?,
-            context.base_repo().list_all_tags().await?.names()
+            context.new_tags().await?.new_tags.names(),
+            context.base_forge().list_all_tags().await?.names()
         );
     });
 
@@ -1,117 +1,600 @@
 extern crate core;
 
-use std::{env, fs, str::FromStr};
+use std::{fs, path::Path, process, time::Duration};
 
-use anyhow::Context as ResultContext;
-use consts::SYNC_PREFIX;
+use anyhow::{Context as ResultContext, Error, Result};
+use clap::Parser;
 use itertools::Itertools;
-use log::info;
+use log::{error, info, warn};
 use pretty_env_logger::init as init_logger;
-use strum::EnumString;
-use Stage::Detect;
 
 use crate::{
+    cli::{Cli, Command, Config},
     context::Context,
-    utils::{Action, RepoHandlerExt},
-    Stage::Sync,
+    utils::{
+        new_tags_json, parse_new_tags, results_json, retries, set_max_rate_limit_wait, Action,
+        BranchTemplate, DetectReport, ErrorKind, PatchStatus, RepoHandlerExt, RetryPolicy,
+        StatusReport, SyncReport, SyncStep, Tag, TagOutcome, TagsExt, PARTIAL_SUCCESS_EXIT_CODE,
+    },
 };
 
+mod cli;
+mod config_file;
 mod consts;
 mod context;
 mod utils;
 
-/// Multiple stages represent the execution state in Github Action.
-#[derive(EnumString)]
-enum Stage {
-    /// Detection stage to detect whether the **base repository** has new tags
-    /// that can be synchronized to the **head repository**.
-    ///
-    /// - Corresponding method: [`Context::new_tags`]
-    Detect,
+/// Default name of the new tags file in the workspace.
+const NEW_TAGS_FILE: &str = "new_tags.json";
 
-    /// Synchronization stage to synchronize tags from the **base repository**
-    /// to the **head repository** as branches.
-    ///
-    /// - Corresponding method: [`Context::sync_tags`]
-    Sync,
-}
+/// Maximum size of the results to be also set as the `results` output.
+const INLINE_RESULTS_LIMIT: usize = 16 * 1024;
 
 #[tokio::main]
 async fn main() {
     init_logger();
 
-    let stage = Stage::from_str(env::args().nth(1).unwrap().as_str());
-    let config = Context::new().unwrap();
-    let new_tags_file = config.github_workspace().join("new_tags.txt");
-    let new_tags_file = new_tags_file.as_path();
-
-    match stage {
-        Ok(Detect) => {
-            let new_tags = config
-                .new_tags()
-                .await
-                .context("Failed to get new tags")
-                .unwrap();
-
-            if new_tags.is_empty() {
-                // Nothing to sync
-                return;
+    let cli = Cli::parse();
+    let config = cli.command.config();
+    RetryPolicy {
+        attempts: config.retry_attempts.max(1),
+        base_delay: Duration::from_millis(config.retry_delay_ms),
+        max_delay: Duration::from_millis(config.retry_max_delay_ms),
+    }
+    .install();
+    set_max_rate_limit_wait(Duration::from_secs(config.rate_limit_max_wait));
+    let workspace = &config.workspace;
+    let configs = config_file::pairs(config).unwrap_or_else(|error| {
+        fail(
+            "Invalid configuration",
+            &error,
+            ErrorKind::Config.exit_code(),
+        )
+    });
+    // A pair failing to set up is skipped, without stopping the other pairs
+    let mut contexts = Vec::new();
+    let mut setup_failures = Vec::new();
+    for config in &configs {
+        match Context::new(config).await {
+            Ok(context) => contexts.push(context),
+            Err(error) => setup_failures.push(PairFailure::setup(config, error)),
+        }
+    }
+
+    match &cli.command {
+        Command::Detect(handoff) => {
+            let mut reports = Vec::new();
+            let mut failures = setup_failures;
+            for context in &contexts {
+                match detect(context).await {
+                    Ok(report) => reports.push(report),
+                    Err(error) => failures.push(PairFailure::detect(context, error)),
+                }
+            }
+            // The new tags of the other pairs are handed over all the same
+            if reports.iter().any(|report| !report.new_tags.is_empty()) {
+                let new_tags_file = handoff
+                    .new_tags_file
+                    .clone()
+                    .unwrap_or_else(|| workspace.join(NEW_TAGS_FILE));
+                if let Err(error) = hand_over(&new_tags_file, &reports) {
+                    failures.push(PairFailure {
+                        title: "Failed to hand the new tags over".to_string(),
+                        error,
+                        code: ErrorKind::Config.exit_code(),
+                    });
+                }
             }
+            fail_pairs(failures, false);
+        }
+        Command::Sync(handoff) => {
+            let new_tags_file = handoff
+                .new_tags_file
+                .clone()
+                .unwrap_or_else(|| workspace.join(NEW_TAGS_FILE));
+            let new_tags = fs::read_to_string(&new_tags_file)
+                .map_err(Error::from)
+                .and_then(|json| parse_new_tags(&json))
+                .context(format!(
+                    "Failed to read new tags from '{}'",
+                    new_tags_file.display()
+                ))
+                .unwrap_or_else(|error| {
+                    fail(
+                        &SyncStep::Detect.title(None),
+                        &error,
+                        ErrorKind::Config.exit_code(),
+                    )
+                });
+            // Each pair syncs the tags detected for it
+            let pairs = contexts
+                .iter()
+                .map(|context| {
+                    let tags = new_tags
+                        .iter()
+                        .filter(|new_tag| new_tag.pair.as_deref() == context.pair())
+                        .map(|new_tag| new_tag.tag.clone())
+                        .collect::<Vec<_>>();
+                    (context, tags)
+                })
+                .filter(|(_, tags)| !tags.is_empty())
+                .collect::<Vec<_>>();
+            sync(&pairs, workspace, setup_failures).await;
+        }
+        Command::Run(_) => {
+            // The new tags are handed over in memory, without any file
+            let mut pairs = Vec::new();
+            let mut failures = setup_failures;
+            for context in &contexts {
+                match detect(context).await {
+                    Ok(report) if !report.new_tags.is_empty() => {
+                        pairs.push((context, report.new_tags))
+                    }
+                    Ok(_) => {}
+                    Err(error) => failures.push(PairFailure::detect(context, error)),
+                }
+            }
+            match pairs.is_empty() {
+                true => fail_pairs(failures, false),
+                false => sync(&pairs, workspace, failures).await,
+            }
+        }
+        Command::Status(_) => {
+            let mut failures = setup_failures;
+            for context in &contexts {
+                let report = match context.status().await {
+                    Ok(report) => report,
+                    Err(error) => {
+                        failures.push(PairFailure::detect(context, error));
+                        continue;
+                    }
+                };
+                if let Some(pair) = context.pair() {
+                    println!("[{pair}]");
+                }
+                print!("{}", status_table(&report, context.branch_template()));
+            }
+            fail_pairs(failures, false);
+        }
+        Command::Prune(_) => {
+            let mut failures = setup_failures;
+            for context in &contexts {
+                let pruned = match context.prune().await {
+                    Ok(pruned) => pruned,
+                    Err(error) => {
+                        failures.push(PairFailure {
+                            title: of_pair("Failed to prune the stale branches", context.pair()),
+                            code: ErrorKind::of(&error, SyncStep::Detect).exit_code(),
+                            error,
+                        });
+                        continue;
+                    }
+                };
+                let verb = match cli.command.config().dry_run {
+                    true => "Would prune",
+                    false => "Pruned",
+                };
+                info!(
+                    "{}: {}",
+                    of_pair(
+                        &format!("{verb} {} stale branches", pruned.len()),
+                        context.pair()
+                    ),
+                    pruned.join(", ")
+                );
+            }
+            fail_pairs(failures, false);
+        }
+    }
+
+    report_retries();
+}
+
+/// Saves the new tags of the `reports` to the `new_tags_file`, which is handed
+/// over to `sync`.
+fn hand_over(new_tags_file: &Path, reports: &[DetectReport]) -> Result<()> {
+    let new_tags_file = write_output_file("new-tags-file", new_tags_file, &new_tags_json(reports)?)
+        .context("Failed to write new tags to file")?;
+    Action::set_env("NEW_TAGS_FILE", &new_tags_file)?;
+    Ok(())
+}
+
+/// Writes the `content` to the `path`, and sets its absolute path as the
+/// `output`, which is returned.
+fn write_output_file(output: &str, path: &Path, content: &str) -> Result<String> {
+    fs::write(path, content)?;
+    let path = path.canonicalize()?;
+    let path = path
+        .to_str()
+        .context(format!("Path '{}' is not valid UTF-8", path.display()))?;
+    Action::set_output(output, path)?;
+    Ok(path.to_string())
+}
 
-            // Save new tags to a file
-            fs::write(new_tags_file, new_tags.join("\n").as_bytes())
-                .context("Failed to write new tags to file")
-                .unwrap();
+/// Detects the new tags, and writes the job summary about them.
+async fn detect(context: &Context) -> Result<DetectReport> {
+    let report = context.new_tags().await?;
+    write_summary(&detect_summary(
+        &report,
+        context.branch_template(),
+        retries(),
+    ));
+    match report.new_tags.is_empty() {
+        true => info!("{}.", of_pair("Nothing to sync", context.pair())),
+        false => info!(
+            "{}: '{}', prepare to sync...",
+            of_pair("New tags found", context.pair()),
+            report.new_tags.clone().names().join(", ")
+        ),
+    }
+    Ok(report)
+}
 
-            Action::set_output(
-                "new-tags-file",
-                new_tags_file.canonicalize().unwrap().to_str().unwrap(),
-            );
+/// Syncs the new tags of each pair, and writes the job summary and the
+/// outputs about them, failing if any tag failed to sync or any of the
+/// `failures` of the pairs happened before.
+///
+/// A pair failing to sync does not stop the following pairs.
+async fn sync(pairs: &[(&Context, Vec<Tag>)], workspace: &Path, mut failures: Vec<PairFailure>) {
+    let mut reports = Vec::new();
+    for (context, new_tags) in pairs {
+        let report = context.sync_tags(new_tags).await;
+        write_summary(&sync_summary(&report, retries()));
+        reports.push(report);
+    }
+    write_sync_outputs(&reports, workspace).unwrap_or_else(|error| {
+        fail(
+            "Failed to write the outputs",
+            &error,
+            ErrorKind::Config.exit_code(),
+        )
+    });
 
-            info!(
-                "New tags found: '{}', prepare to sync...",
-                new_tags.join(", ")
-            );
+    // Tell the workflow whether any tag was synced before the failures
+    let partial = reports
+        .iter()
+        .any(|report| report.synced().next().is_some());
+    let dry_run = reports.iter().any(|report| report.dry_run);
+    // The failures which do not fail their sync are only warned about
+    for report in reports {
+        let failed = report.failed();
+        for failure in report.failures {
+            let title = of_pair(&failure.title(), report.pair.as_deref());
+            match failed {
+                true => failures.push(PairFailure {
+                    title,
+                    code: failure.kind().exit_code(),
+                    error: failure.error,
+                }),
+                false => Action::warning(&title, &format!("{:#}", failure.error)),
+            }
         }
-        Ok(Sync) => {
-            let file_content = fs::read_to_string(new_tags_file)
-                .context("Failed to read new tags from file")
-                .unwrap();
-            let new_tags = file_content.split('\n').collect::<Vec<_>>();
-
-            config
-                .sync_tags(&new_tags)
-                .await
-                .context("Failed to sync new tags")
-                .unwrap();
-
-            // Save synced branches to a file
-            let synced_branches_file = config.github_workspace().join("synced_branches.txt");
-            let synced_branches_file = synced_branches_file.as_path();
-            fs::write(
-                synced_branches_file,
-                new_tags
-                    .iter()
-                    .map(|tag| format!("{}{tag}", SYNC_PREFIX))
-                    .join("\n")
-                    .as_bytes(),
-            )
-            .context("Failed to write synced branches to file")
-            .unwrap();
-
-            Action::set_output(
-                "synced-branches-file",
-                synced_branches_file
-                    .canonicalize()
-                    .unwrap()
-                    .to_str()
-                    .unwrap(),
-            );
-
-            info!("Synced successfully.");
+    }
+    fail_pairs(failures, partial);
+
+    match dry_run {
+        true => info!("Dry run finished, nothing was pushed."),
+        false => info!("Synced successfully."),
+    }
+}
+
+/// Writes the synced branches and the results of the tags of all the
+/// `reports` to the files of the outputs.
+fn write_sync_outputs(reports: &[SyncReport], workspace: &Path) -> Result<()> {
+    let synced_branches = reports
+        .iter()
+        .flat_map(SyncReport::synced)
+        .map(|tag| &tag.branch)
+        .join("\n");
+    write_output_file(
+        "synced-branches-file",
+        &workspace.join("synced_branches.txt"),
+        &synced_branches,
+    )
+    .context("Failed to write synced branches to file")?;
+
+    // Save the results of all tags to a JSON file, and inline them too
+    // if they are small enough for an output
+    let results = results_json(reports)?;
+    write_output_file("results-file", &workspace.join("results.json"), &results)
+        .context("Failed to write results to file")?;
+    if results.len() <= INLINE_RESULTS_LIMIT {
+        Action::set_output("results", &results)?;
+    }
+    Ok(())
+}
+
+/// Appends the name of the `pair` of repositories to the `title`, if any.
+fn of_pair(title: &str, pair: Option<&str>) -> String {
+    match pair {
+        Some(pair) => format!("{title} in pair {pair}"),
+        None => title.to_string(),
+    }
+}
+
+/// Failure of a pair of repositories, which is reported once all the pairs
+/// are done.
+struct PairFailure {
+    title: String,
+    error: Error,
+    code: i32,
+}
+
+impl PairFailure {
+    /// Returns the failure of the pair of the `config` to set up.
+    fn setup(config: &Config, error: Error) -> Self {
+        // Looking up the repositories may fail on the forge or network,
+        // otherwise the settings are invalid
+        let kind = ErrorKind::typed(&error).unwrap_or(ErrorKind::Config);
+        Self {
+            title: of_pair("Failed to set up", config.pair.as_deref()),
+            code: kind.exit_code(),
+            error,
         }
-        Err(e) => {
-            panic!("Invalid stage: {}", e);
+    }
+
+    /// Returns the failure of the `context` to detect its tags.
+    fn detect(context: &Context, error: Error) -> Self {
+        Self {
+            title: of_pair(&SyncStep::Detect.title(None), context.pair()),
+            code: ErrorKind::of(&error, SyncStep::Detect).exit_code(),
+            error,
         }
     }
 }
+
+/// Reports the other `failures` as annotations, before failing with the first
+/// one, if any. The exit code is the [`PARTIAL_SUCCESS_EXIT_CODE`] if some
+/// tags were synced, as told by `partial`.
+fn fail_pairs(mut failures: Vec<PairFailure>, partial: bool) {
+    if failures.is_empty() {
+        return;
+    }
+    let first = failures.remove(0);
+    for other in failures {
+        Action::error(&other.title, &format!("{:#}", other.error));
+    }
+    let code = match partial {
+        true => PARTIAL_SUCCESS_EXIT_CODE,
+        false => first.code,
+    };
+    fail(&first.title, &first.error, code)
+}
+
+/// Reports the `error` as an annotation with the `title`, and exits with the
+/// `code` of its [`ErrorKind`], or the [`PARTIAL_SUCCESS_EXIT_CODE`].
+fn fail(title: &str, error: &Error, code: i32) -> ! {
+    error!("{title}: {error:?}");
+    Action::error(title, &format!("{error:#}"));
+    report_retries();
+    process::exit(code)
+}
+
+/// Appends the `markdown` to the job summary, which is not worth failing the
+/// run for.
+fn write_summary(markdown: &str) {
+    if let Err(err) = Action::add_summary(markdown) {
+        warn!("Failed to write the job summary: {err:#}");
+    }
+}
+
+/// Renders the job summary of [`detect`], naming the branches with the
+/// `template`.
+fn detect_summary(report: &DetectReport, template: &BranchTemplate, retries: usize) -> String {
+    let mut summary = format!(
+        "### Detected {} new tags{}\n\nChecked {} tags of the base repository in {}.\n",
+        report.new_tags.len(),
+        in_pair(report.pair.as_deref()),
+        report.base_tags,
+        seconds(report.duration),
+    );
+    if !report.new_tags.is_empty() {
+        summary.push_str("\n| Tag | Branch |\n| --- | --- |\n");
+        for Tag { name, .. } in &report.new_tags {
+            summary.push_str(&format!("| `{name}` | `{}` |\n", template.branch(name)));
+        }
+    }
+    summary.push_str(&retries_note(retries));
+    summary
+}
+
+/// Renders the job summary of [`sync`].
+///
+/// The branches of a dry run are shown with the head commit they would have
+/// been pushed at.
+fn sync_summary(report: &SyncReport, retries: usize) -> String {
+    let mut summary = format!(
+        "### {} {} of {} tags{}\n\n\
+        | Tag | Branch | Patch | Duration | Links |\n\
+        | --- | --- | --- | --- | --- |\n",
+        match report.dry_run {
+            true => "Dry run: would sync",
+            false => "Synced",
+        },
+        report.synced().count(),
+        report.tags.len(),
+        in_pair(report.pair.as_deref()),
+    );
+    for tag in &report.tags {
+        let branch = match (&tag.branch_url, &tag.head_sha) {
+            (Some(url), _) => format!("[`{}`]({url})", tag.branch),
+            (None, Some(sha)) if report.dry_run => format!("`{}` at `{:.7}`", tag.branch, sha),
+            (None, _) => format!("`{}`", tag.branch),
+        };
+        let patch = match &tag.outcome {
+            TagOutcome::Synced { patch: None } => "none".to_string(),
+            TagOutcome::Synced {
+                patch: Some(PatchStatus::Clean),
+            } => "applied".to_string(),
+            TagOutcome::Synced {
+                patch: Some(PatchStatus::ThreeWay),
+            } => "applied with a three-way merge".to_string(),
+            TagOutcome::Skipped => "skipped".to_string(),
+            TagOutcome::Failed {
+                step, conflicts, ..
+            } if conflicts.is_empty() => format!("failed at {step}"),
+            TagOutcome::Failed { conflicts, .. } => format!(
+                "conflicts with {}",
+                conflicts.iter().map(|file| format!("`{file}`")).join(", ")
+            ),
+        };
+        let links = [
+            ("compare", &tag.compare_url),
+            ("pull request", &tag.pull_request),
+            ("release", &tag.release),
+        ]
+        .into_iter()
+        .filter_map(|(name, url)| Some(format!("[{name}]({})", url.as_ref()?)))
+        .join(", ");
+        summary.push_str(&format!(
+            "| `{}` | {branch} | {patch} | {} | {links} |\n",
+            tag.tag,
+            seconds(tag.duration),
+        ));
+    }
+    let count = |failed: bool| {
+        report
+            .tags
+            .iter()
+            .filter(|tag| match tag.outcome {
+                TagOutcome::Failed { .. } => failed,
+                TagOutcome::Skipped => !failed,
+                TagOutcome::Synced { .. } => false,
+            })
+            .count()
+    };
+    summary.push_str(&format!(
+        "\nFinished in {}: {} synced, {} failed, {} skipped ({}).\n",
+        seconds(report.duration),
+        report.synced().count(),
+        count(true),
+        count(false),
+        report.policy,
+    ));
+    // The failures of the tags are shown below, unlike the one preventing
+    // the sync from starting
+    for failure in report
+        .failures
+        .iter()
+        .filter(|failure| failure.tag.is_none())
+    {
+        summary.push_str(&format!(
+            "\n{}:\n\n```\n{:#}\n```\n",
+            failure.title(),
+            failure.error
+        ));
+    }
+    for tag in &report.tags {
+        if let TagOutcome::Failed { error, .. } = &tag.outcome {
+            summary.push_str(&format!(
+                "\nFailed to sync `{}`:\n\n```\n{error}\n```\n",
+                tag.tag
+            ));
+        }
+    }
+    summary.push_str(&retries_note(retries));
+    summary
+}
+
+/// Renders the state of each tag shown by the `status` command, naming the
+/// branches with the `template`.
+fn status_table(report: &StatusReport, template: &BranchTemplate) -> String {
+    let mut table = format!(
+        "{} synced, {} pending, {} stale\n",
+        report.synced.len(),
+        report.pending.len(),
+        report.stale.len(),
+    );
+    for tag in &report.synced {
+        table.push_str(&format!("synced   {tag} -> {}\n", template.branch(tag)));
+    }
+    for tag in &report.pending {
+        table.push_str(&format!("pending  {tag}\n"));
+    }
+    for branch in &report.stale {
+        table.push_str(&format!("stale    {branch}\n"));
+    }
+    table
+}
+
+/// Names the `pair` of repositories in the headings of the summaries, if any.
+fn in_pair(pair: Option<&str>) -> String {
+    match pair {
+        Some(pair) => format!(" in `{pair}`"),
+        None => String::new(),
+    }
+}
+
+fn retries_note(retries: usize) -> String {
+    match retries {
+        0 => String::new(),
+        n => format!("\nNetwork operations were retried {n} times.\n"),
+    }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+/// Logs how many times the network operations have been retried.
+fn report_retries() {
+    match retries() {
+        0 => info!("No network operation needed to be retried."),
+        n => warn!("Network operations were retried {n} times in total."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::*;
+    use crate::{test_fn, utils::TagReport};
+
+    test_fn!(render_sync_summary {
+        let mut synced = TagReport::new("v1.0", "sync-v1.0".to_string());
+        synced.outcome = TagOutcome::Synced {
+            patch: Some(PatchStatus::ThreeWay),
+        };
+        synced.duration = Duration::from_millis(1500);
+        synced.branch_url = Some(Url::parse("https://github.com/o/r/tree/sync-v1.0")?);
+        synced.release = Some(Url::parse("https://github.com/o/r/releases/tag/v1.0")?);
+        let mut failed = TagReport::new("v2.0", "sync-v2.0".to_string());
+        failed.outcome = TagOutcome::Failed {
+            step: SyncStep::Patch,
+            error: "Failed to apply patch".to_string(),
+            conflicts: vec!["Cargo.toml".to_string()],
+        };
+        let skipped = TagReport::new("v3.0", "sync-v3.0".to_string());
+        let report = SyncReport {
+            pair: Some("linux".to_string()),
+            tags: vec![synced, failed, skipped],
+            ..Default::default()
+        };
+
+        let summary = sync_summary(&report, 2);
+        assert!(summary.starts_with("### Synced 1 of 3 tags in `linux`\n"));
+        assert!(summary.contains(
+            "| `v1.0` | [`sync-v1.0`](https://github.com/o/r/tree/sync-v1.0) \
+            | applied with a three-way merge | 1.5s \
+            | [release](https://github.com/o/r/releases/tag/v1.0) |"
+        ));
+        assert!(summary.contains("| `v2.0` | `sync-v2.0` | conflicts with `Cargo.toml` | 0.0s |  |"));
+        assert!(summary.contains("| `v3.0` | `sync-v3.0` | skipped |"));
+        assert!(summary.contains("Finished in 0.0s: 1 synced, 1 failed, 1 skipped (fail-fast).\n"));
+        assert!(summary.contains("Failed to sync `v2.0`:\n\n```\nFailed to apply patch\n```"));
+        assert!(summary.ends_with("Network operations were retried 2 times.\n"));
+
+        let mut planned = TagReport::new("v1.0", "sync-v1.0".to_string());
+        planned.outcome = TagOutcome::Synced { patch: None };
+        planned.head_sha = Some("0123456789".repeat(4));
+        let report = SyncReport {
+            tags: vec![planned],
+            dry_run: true,
+            ..Default::default()
+        };
+        let summary = sync_summary(&report, 0);
+        assert!(summary.starts_with("### Dry run: would sync 1 of 1 tags\n"));
+        assert!(summary.contains("| `v1.0` | `sync-v1.0` at `0123456` | none |"));
+    });
+}
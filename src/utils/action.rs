@@ -0,0 +1,197 @@
+//! Workflow commands of GitHub Actions.
+//!
+//! See <https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions>.
+
+use std::{
+    env,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use rand::{distributions::Alphanumeric, Rng};
+
+pub struct Action;
+
+impl Action {
+    /// Sets the output `key` of the current step to the `value`, which may
+    /// span multiple lines.
+    ///
+    /// Falls back to the deprecated `::set-output` command when
+    /// `$GITHUB_OUTPUT` is not available, e.g. on older runners.
+    pub fn set_output(key: &str, value: &str) -> Result<()> {
+        match file_command("GITHUB_OUTPUT") {
+            Some(path) => append(&path, &key_value(key, value)?),
+            None => {
+                println!(
+                    "::set-output name={}::{}",
+                    escape_property(key),
+                    escape_data(value)
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Exports the environment variable `key` to the following steps, which
+    /// does nothing if `$GITHUB_ENV` is not available.
+    ///
+    /// Unlike the outputs, there is no fallback, as GitHub disabled the
+    /// `::set-env` command for letting the logs inject variables.
+    pub fn set_env(key: &str, value: &str) -> Result<()> {
+        match file_command("GITHUB_ENV") {
+            Some(path) => append(&path, &key_value(key, value)?),
+            None => {
+                debug!("Not exporting {key}, as $GITHUB_ENV is not available");
+                Ok(())
+            }
+        }
+    }
+
+    /// Prepends the `path` to the `PATH` of the following steps, which does
+    /// nothing if `$GITHUB_PATH` is not available, as the `::add-path` command
+    /// is disabled.
+    // tags-sync itself leaves the PATH of the workflow alone
+    #[allow(dead_code)]
+    pub fn add_path(path: &Path) -> Result<()> {
+        let path = path.display();
+        match file_command("GITHUB_PATH") {
+            Some(file) => append(&file, &format!("{path}\n")),
+            None => {
+                debug!("Not adding {path} to the PATH, as $GITHUB_PATH is not available");
+                Ok(())
+            }
+        }
+    }
+
+    /// Appends the `markdown` to the job summary, which does nothing if
+    /// `$GITHUB_STEP_SUMMARY` is not available.
+    pub fn add_summary(markdown: &str) -> Result<()> {
+        match file_command("GITHUB_STEP_SUMMARY") {
+            Some(path) => append(&path, markdown),
+            None => Ok(()),
+        }
+    }
+
+    /// Emits an error annotation, which shows up in the run and pull request
+    /// UI.
+    pub fn error(title: &str, message: &str) {
+        println!(
+            "::error title={}::{}",
+            escape_property(title),
+            escape_data(message)
+        );
+    }
+
+    /// Emits a warning annotation, which shows up in the run and pull request
+    /// UI.
+    pub fn warning(title: &str, message: &str) {
+        println!(
+            "::warning title={}::{}",
+            escape_property(title),
+            escape_data(message)
+        );
+    }
+
+    /// Masks the `value` in the logs, e.g. a token created at runtime.
+    pub fn add_mask(value: &str) {
+        println!("::add-mask::{}", escape_data(value));
+    }
+
+    /// Starts a collapsible group of log lines, until [`Action::end_group`].
+    pub fn group(title: &str) {
+        println!("::group::{}", escape_data(title));
+    }
+
+    pub fn end_group() {
+        println!("::endgroup::");
+    }
+
+    /// Returns the link to the current workflow run, if running in GitHub
+    /// Actions.
+    pub fn run_url() -> Option<String> {
+        let server = env::var("GITHUB_SERVER_URL").ok()?;
+        let repository = env::var("GITHUB_REPOSITORY").ok()?;
+        let run_id = env::var("GITHUB_RUN_ID").ok()?;
+        Some(format!(
+            "{}/{repository}/actions/runs/{run_id}",
+            server.trim_end_matches('/')
+        ))
+    }
+}
+
+/// Returns the file of the command given by the environment variable `key`,
+/// if the runner provides it.
+fn file_command(key: &str) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+fn append(path: &Path, content: &str) -> Result<()> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .context(format!("Failed to write to '{}'", path.display()))
+}
+
+/// Formats the `key` and `value` for a command file, using a random delimiter
+/// so that the `value` may span multiple lines.
+fn key_value(key: &str, value: &str) -> Result<String> {
+    let random = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>();
+    let delimiter = format!("ghadelimiter_{random}");
+    if key.contains(&delimiter) || value.contains(&delimiter) {
+        bail!("Unexpected delimiter '{delimiter}' in '{key}'");
+    }
+    Ok(format!("{key}<<{delimiter}\n{value}\n{delimiter}\n"))
+}
+
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(property: &str) -> String {
+    escape_data(property)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(write_multiline_value {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("output");
+        append(&path, &key_value("new-tags", "v1.0\nv2.0")?)?;
+        append(&path, &key_value("empty", "")?)?;
+
+        let content = fs::read_to_string(&path)?;
+        let lines = content.lines().collect::<Vec<_>>();
+        let delimiter = lines[0].strip_prefix("new-tags<<").unwrap();
+        assert!(delimiter.starts_with("ghadelimiter_"));
+        assert_eq!(lines[1..4], ["v1.0", "v2.0", delimiter]);
+        let delimiter = lines[4].strip_prefix("empty<<").unwrap();
+        assert_eq!(lines[5..], ["", delimiter]);
+    });
+
+    test_fn!(escape_commands {
+        assert_eq!(escape_data("100%\r\ndone"), "100%25%0D%0Adone");
+        assert_eq!(escape_property("a:b,c"), "a%3Ab%2Cc");
+    });
+}
@@ -0,0 +1,84 @@
+//! Names of the branches synced from the tags.
+
+use std::{
+    fmt,
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+/// Template of the name of the branch synced from each tag, where `{tag}` is
+/// replaced with the name of the tag, e.g. `sync-{tag}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchTemplate {
+    prefix: String,
+    suffix: String,
+}
+
+impl BranchTemplate {
+    /// Returns the name of the branch synced from the `tag`.
+    pub fn branch(&self, tag: &str) -> String {
+        format!("{}{tag}{}", self.prefix, self.suffix)
+    }
+
+    /// Returns the name of the tag that the `branch` is synced from, if the
+    /// branch matches this template.
+    pub fn tag<'a>(&self, branch: &'a str) -> Option<&'a str> {
+        branch
+            .strip_prefix(&self.prefix)?
+            .strip_suffix(&self.suffix)
+            .filter(|tag| !tag.is_empty())
+    }
+}
+
+impl Default for BranchTemplate {
+    fn default() -> Self {
+        Self {
+            prefix: "sync-".to_string(),
+            suffix: String::new(),
+        }
+    }
+}
+
+impl FromStr for BranchTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split("{tag}").collect::<Vec<_>>()[..] {
+            // Without any text around the tag, every branch would look synced
+            ["", ""] => bail!("'{value}' must contain some text around '{{tag}}'."),
+            [prefix, suffix] => Ok(Self {
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+            }),
+            _ => bail!("'{value}' must contain '{{tag}}' exactly once."),
+        }
+    }
+}
+
+impl Display for BranchTemplate {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{}{{tag}}{}", self.prefix, self.suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(parse_branch_template {
+        let template = "upstream/{tag}-patched".parse::<BranchTemplate>()?;
+        assert_eq!(template.branch("v1.0"), "upstream/v1.0-patched");
+        assert_eq!(template.tag("upstream/v1.0-patched"), Some("v1.0"));
+        assert_eq!(template.tag("upstream/-patched"), None);
+        assert_eq!(template.tag("main"), None);
+        assert_eq!(template.to_string(), "upstream/{tag}-patched");
+
+        assert_eq!(BranchTemplate::default().branch("v1.0"), "sync-v1.0");
+        assert!("{tag}".parse::<BranchTemplate>().is_err());
+        assert!("sync-{tag}-{tag}".parse::<BranchTemplate>().is_err());
+        assert!("sync".parse::<BranchTemplate>().is_err());
+    });
+}
@@ -0,0 +1,84 @@
+//! A disk cache of API responses, which allows us to send conditional requests.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// A response cached by its `ETag`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    /// Value of the `ETag` header, sent back as `If-None-Match`.
+    pub etag: Option<String>,
+    /// Value of the `Link` header, which is needed for pagination.
+    pub link: Option<String>,
+    /// Response body.
+    pub body: String,
+}
+
+/// Cache directory of the API responses, keyed by the request URL.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the cached response of the given `route`, if any.
+    ///
+    /// A broken cache entry is treated as absent, so that the route is
+    /// requested again from scratch.
+    pub fn get(&self, route: &str) -> Option<CachedResponse> {
+        let content = fs::read_to_string(self.path(route)).ok()?;
+        serde_json::from_str(&content)
+            .map_err(|e| debug!("Ignore broken cache of '{route}': {e}"))
+            .ok()
+    }
+
+    /// Stores the `response` of the given `route`.
+    pub fn put(&self, route: &str, response: &CachedResponse) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .context(format!("Failed to create cache directory: {:?}", self.dir))?;
+        fs::write(self.path(route), serde_json::to_vec(response)?)
+            .context(format!("Failed to cache the response of '{route}'"))
+    }
+
+    fn path(&self, route: &str) -> PathBuf {
+        let name = route
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        self.dir.join(format!("{name}.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(cache_response {
+        let tmp_dir = tempdir()?;
+        let cache = ResponseCache::new(tmp_dir.path().join("cache"));
+        let route = "repos/vuejs/vue/tags?per_page=100&page=1";
+        assert!(cache.get(route).is_none());
+
+        cache.put(route, &CachedResponse {
+            etag: Some("W/\"abc\"".to_string()),
+            link: None,
+            body: "[]".to_string(),
+        })?;
+        let cached = cache.get(route).unwrap();
+        assert_eq!(cached.etag.as_deref(), Some("W/\"abc\""));
+        assert_eq!(cached.body, "[]");
+
+        // Other routes are not affected
+        assert!(cache.get("repos/vuejs/vue/tags?per_page=100&page=2").is_none());
+    });
+}
@@ -0,0 +1,223 @@
+//! A GitHub Check Run reporting the result of each synced tag.
+
+use anyhow::Result;
+use chrono::Utc;
+use octocrab::Octocrab;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::utils::{send, GitHubRepo, PatchStatus, TagOutcome, TagReport};
+
+/// Name of the check shown on the commit.
+pub const CHECK_NAME: &str = "tags-sync";
+
+/// Maximum number of annotations that a single update of a check run takes.
+const MAX_ANNOTATIONS: usize = 50;
+
+/// A check run on the latest commit of the default branch of a repository,
+/// which is updated as the tags are synced.
+pub struct CheckRun<'octo> {
+    api: &'octo Octocrab,
+    route: String,
+    /// Rows of the summary table, one per reported tag.
+    rows: Vec<String>,
+    failed: usize,
+}
+
+#[derive(Deserialize)]
+struct Commit {
+    sha: String,
+}
+
+#[derive(Deserialize)]
+struct Created {
+    id: u64,
+}
+
+impl<'octo> CheckRun<'octo> {
+    /// Starts a check run in the `repo`, which needs the `checks: write`
+    /// permission.
+    pub async fn start(repo: &GitHubRepo<'octo>) -> Result<CheckRun<'octo>> {
+        let route = format!("repos/{}/{}", repo.owner(), repo.name());
+        let commit = send(
+            repo.api(),
+            Method::GET,
+            &format!("{route}/commits/HEAD"),
+            |builder| builder,
+        )
+        .await?
+        .json::<Commit>()
+        .await?;
+
+        let body = json!({
+            "name": CHECK_NAME,
+            "head_sha": commit.sha,
+            "status": "in_progress",
+            "started_at": Utc::now(),
+        });
+        let created = send(
+            repo.api(),
+            Method::POST,
+            &format!("{route}/check-runs"),
+            |builder| builder.json(&body),
+        )
+        .await?
+        .json::<Created>()
+        .await?;
+
+        Ok(Self {
+            api: repo.api(),
+            route: format!("{route}/check-runs/{}", created.id),
+            rows: Vec::new(),
+            failed: 0,
+        })
+    }
+
+    /// Adds the outcome of the `tag` to the check run.
+    pub async fn report(&mut self, tag: &TagReport) -> Result<()> {
+        let (result, annotations) = annotate(&tag.tag, &tag.outcome);
+        self.rows
+            .push(format!("| `{}` | `{}` | {result} |", tag.tag, tag.branch));
+        if let TagOutcome::Failed { .. } = tag.outcome {
+            self.failed += 1;
+        }
+        // The annotations are appended to the ones of the previous updates
+        for annotations in chunk_annotations(annotations) {
+            self.update(json!({ "output": self.output(annotations) }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Completes the check run, which fails if any tag failed to sync.
+    pub async fn finish(self) -> Result<()> {
+        let conclusion = match self.failed {
+            0 => "success",
+            _ => "failure",
+        };
+        self.update(json!({
+            "status": "completed",
+            "conclusion": conclusion,
+            "completed_at": Utc::now(),
+            "output": self.output(Vec::new()),
+        }))
+        .await
+    }
+
+    async fn update(&self, body: Value) -> Result<()> {
+        send(self.api, Method::PATCH, &self.route, |builder| {
+            builder.json(&body)
+        })
+        .await?;
+        Ok(())
+    }
+
+    fn output(&self, annotations: Vec<Value>) -> Value {
+        let title = match self.failed {
+            0 => format!("{} tags synced", self.rows.len()),
+            failed => format!("{failed} of {} tags failed to sync", self.rows.len()),
+        };
+        let summary = format!(
+            "| Tag | Branch | Result |\n| --- | --- | --- |\n{}",
+            self.rows.join("\n")
+        );
+        json!({
+            "title": title,
+            "summary": summary,
+            "annotations": annotations,
+        })
+    }
+}
+
+/// Returns the result shown in the summary table for the `outcome` of the
+/// `tag`, and its annotations.
+///
+/// Only the files that the patch conflicts with are annotated, as an
+/// annotation must point to a file of the repository.
+fn annotate(tag: &str, outcome: &TagOutcome) -> (String, Vec<Value>) {
+    match outcome {
+        TagOutcome::Synced { patch } => {
+            let result = match patch {
+                Some(PatchStatus::Clean) => "applied",
+                Some(PatchStatus::ThreeWay) => "applied with a three-way merge",
+                None => "synced without a patch",
+            };
+            (result.to_string(), Vec::new())
+        }
+        TagOutcome::Skipped => (
+            "skipped after an earlier tag failed to sync".to_string(),
+            Vec::new(),
+        ),
+        TagOutcome::Failed {
+            step,
+            error,
+            conflicts,
+        } => {
+            // Keep the error on a single cell of the table
+            let error = error.replace('|', "\\|").replace('\n', " ");
+            let annotations = conflicts
+                .iter()
+                .map(|file| {
+                    json!({
+                        "path": file,
+                        "start_line": 1,
+                        "end_line": 1,
+                        "annotation_level": "failure",
+                        "title": tag,
+                        "message": format!("The patch does not apply to `{file}`"),
+                    })
+                })
+                .collect();
+            (format!("step '{step}' failed: {error}"), annotations)
+        }
+    }
+}
+
+/// Splits the `annotations` into the updates of a check run, which are sent
+/// even without any annotation to update the summary.
+fn chunk_annotations(annotations: Vec<Value>) -> Vec<Vec<Value>> {
+    match annotations.is_empty() {
+        true => vec![Vec::new()],
+        false => annotations
+            .chunks(MAX_ANNOTATIONS)
+            .map(<[Value]>::to_vec)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_fn, utils::SyncStep};
+
+    test_fn!(annotate_outcomes {
+        let outcome = TagOutcome::Synced {
+            patch: Some(PatchStatus::ThreeWay),
+        };
+        let (result, annotations) = annotate("v1.0", &outcome);
+        assert_eq!(result, "applied with a three-way merge");
+        assert!(annotations.is_empty());
+
+        let outcome = TagOutcome::Failed {
+            step: SyncStep::Patch,
+            error: "Failed to apply patch to sync-v2.0".to_string(),
+            conflicts: vec!["Cargo.toml".to_string()],
+        };
+        let (result, annotations) = annotate("v2.0", &outcome);
+        assert_eq!(result, "step 'patch' failed: Failed to apply patch to sync-v2.0");
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0]["path"], "Cargo.toml");
+        assert_eq!(annotations[0]["annotation_level"], "failure");
+    });
+
+    test_fn!(chunk_many_annotations {
+        let annotations = vec![json!({}); 120];
+        let chunks = chunk_annotations(annotations)
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, [50, 50, 20]);
+        assert_eq!(chunk_annotations(Vec::new()), [Vec::<Value>::new()]);
+    });
+}
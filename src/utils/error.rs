@@ -0,0 +1,207 @@
+//! Classes of the errors that make tags-sync fail, and their exit codes.
+
+use std::{
+    fmt::{self, Formatter},
+    io,
+};
+
+use anyhow::Error;
+use git2::{ErrorClass, ErrorCode};
+use strum::Display;
+
+use crate::utils::{PatchConflict, SyncStep};
+
+/// Exit code when some tags were synced, but then another one failed.
+pub const PARTIAL_SUCCESS_EXIT_CODE: i32 = 16;
+
+/// Class of an error that makes tags-sync fail, which decides its exit code
+/// so that the workflows can branch on it.
+///
+/// The exit codes start at 10, so that they do not overlap the usage errors
+/// of the command line, which exit with 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ErrorKind {
+    /// Invalid flags, environment or configuration file, or a file of the
+    /// workspace which cannot be read or written.
+    Config,
+    /// The API of a forge rejected a request.
+    Forge,
+    /// The network failed, even after retrying.
+    Network,
+    /// A git operation on the local clone failed.
+    Git,
+    /// The patch conflicts with a synced tag.
+    PatchConflict,
+    /// Something done after the branch was pushed failed, such as opening its
+    /// pull request or creating its release.
+    Hook,
+}
+
+impl ErrorKind {
+    /// Returns the class of the `error` that happened at the `step`.
+    ///
+    /// The class is the one of the [`SyncError`] among the causes of the
+    /// error, or the one of the step if none of them is.
+    pub fn of(error: &Error, step: SyncStep) -> Self {
+        match (Self::typed(error), step) {
+            (Some(Self::PatchConflict), _) => Self::PatchConflict,
+            (_, SyncStep::Hook) => Self::Hook,
+            (Some(kind), _) => kind,
+            (None, SyncStep::Detect) => Self::Forge,
+            (None, _) => Self::Git,
+        }
+    }
+
+    /// Returns the class of the [`SyncError`] among the causes of the
+    /// `error`, if any.
+    pub fn typed(error: &Error) -> Option<Self> {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<SyncError>())
+            .map(SyncError::kind)
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Config => 10,
+            Self::Forge => 11,
+            Self::Network => 12,
+            Self::Git => 13,
+            Self::PatchConflict => 14,
+            Self::Hook => 15,
+        }
+    }
+}
+
+/// Error of the forge, git and patch layers, classified where it happens.
+#[derive(Debug)]
+pub enum SyncError {
+    /// The API of a forge rejected a request.
+    Forge(Error),
+    /// The network failed, even after retrying.
+    Network(Error),
+    /// A git operation failed.
+    Git(Error),
+    /// The patch conflicts with a synced tag.
+    PatchConflict(PatchConflict),
+}
+
+impl SyncError {
+    /// Classifies the `error` of a request to the API of a forge.
+    pub fn request(error: Error) -> Self {
+        match error.chain().any(is_network) {
+            true => Self::Network(error),
+            false => Self::Forge(error),
+        }
+    }
+
+    /// Classifies the `error` of a git operation.
+    pub fn git(error: Error) -> Self {
+        match error.chain().any(is_network) {
+            true => Self::Network(error),
+            false => Self::Git(error),
+        }
+    }
+
+    /// Returns the error wrapped by this one, if any.
+    pub fn wrapped(&self) -> Option<&Error> {
+        match self {
+            Self::Forge(error) | Self::Network(error) | Self::Git(error) => Some(error),
+            Self::PatchConflict(_) => None,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Forge(_) => ErrorKind::Forge,
+            Self::Network(_) => ErrorKind::Network,
+            Self::Git(_) => ErrorKind::Git,
+            Self::PatchConflict(_) => ErrorKind::PatchConflict,
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Forge(error) | Self::Network(error) | Self::Git(error) => write!(fmt, "{error}"),
+            Self::PatchConflict(conflict) => write!(fmt, "{conflict}"),
+        }
+    }
+}
+
+// The error is transparent, its causes are the ones of the wrapped error
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Forge(error) | Self::Network(error) | Self::Git(error) => error.source(),
+            Self::PatchConflict(conflict) => std::error::Error::source(conflict),
+        }
+    }
+}
+
+/// Returns `true` if the `cause` is a failure to reach a server.
+fn is_network(cause: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+        return error.status().is_none()
+            && (error.is_timeout() || error.is_connect() || error.is_request() || error.is_body());
+    }
+    if let Some(error) = cause.downcast_ref::<git2::Error>() {
+        return matches!(
+            error.class(),
+            ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Ssl
+        ) && !matches!(error.code(), ErrorCode::Auth | ErrorCode::Certificate);
+    }
+    if let Some(error) = cause.downcast_ref::<io::Error>() {
+        return matches!(
+            error.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::TimedOut
+        );
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(classify_errors {
+        let net = git2::Error::new(ErrorCode::GenericError, ErrorClass::Net, "Connection reset");
+        let error = Error::new(SyncError::git(net.into())).context("Failed to push sync-v1.0");
+        assert_eq!(ErrorKind::of(&error, SyncStep::Push), ErrorKind::Network);
+        assert_eq!(ErrorKind::of(&error, SyncStep::Hook), ErrorKind::Hook);
+
+        let auth = git2::Error::new(ErrorCode::Auth, ErrorClass::Http, "Unauthorized");
+        let error = Error::new(SyncError::git(auth.into())).context("Failed to push sync-v1.0");
+        assert_eq!(ErrorKind::of(&error, SyncStep::Push), ErrorKind::Git);
+
+        let forge_message = "GitHub API request 'repos/o/r' failed with 404 Not Found";
+        let error = Error::new(SyncError::Forge(Error::msg(forge_message)));
+        assert_eq!(ErrorKind::of(&error, SyncStep::Checkout), ErrorKind::Forge);
+        assert_eq!(ErrorKind::typed(&error), Some(ErrorKind::Forge));
+        // The error is transparent, so that its message is not repeated
+        let error = error.context("Failed to list tags");
+        assert_eq!(format!("{error:#}"), format!("Failed to list tags: {forge_message}"));
+        let error = Error::msg("Tag v1.0 was detected at a, but now points to b");
+        assert_eq!(ErrorKind::of(&error, SyncStep::Checkout), ErrorKind::Git);
+        assert_eq!(ErrorKind::of(&error, SyncStep::Detect), ErrorKind::Forge);
+        assert_eq!(ErrorKind::typed(&error), None);
+
+        let codes = [
+            ErrorKind::Config,
+            ErrorKind::Forge,
+            ErrorKind::Network,
+            ErrorKind::Git,
+            ErrorKind::PatchConflict,
+            ErrorKind::Hook,
+        ]
+        .map(ErrorKind::exit_code);
+        // 2 is the exit code of the usage errors of the command line
+        assert!(codes.iter().all(|code| *code >= 10 && *code != PARTIAL_SUCCESS_EXIT_CODE));
+    });
+}
@@ -0,0 +1,262 @@
+//! Abstraction over the code forges hosting the repositories.
+
+use std::{
+    env, fmt,
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+
+use crate::utils::{PatchStatus, PullRequest, RepoHandlerExt};
+
+/// User name and password (token) used for the git credentials.
+pub type Credentials = (String, String);
+
+/// Settings of the instances which may host a repository, and of the
+/// credentials to access it, where the empty ones are ignored.
+#[derive(Clone, Default)]
+pub struct HostSettings {
+    /// Web URL of the GitHub instance.
+    pub web_url: Option<String>,
+    /// API URL of the GitHub instance.
+    pub api_url: Option<String>,
+    /// Token used to access the GitHub instance.
+    pub github_token: Option<String>,
+    /// Web URL of the GitLab instance.
+    pub gitlab_url: Option<String>,
+    /// API URL of the GitLab instance, derived from its web URL if not given.
+    pub gitlab_api_url: Option<String>,
+    /// Token used to access the GitLab instance.
+    pub gitlab_token: Option<String>,
+    /// Web URL of the Gitea instance.
+    pub gitea_url: Option<String>,
+    /// API URL of the Gitea instance, derived from its web URL if not given.
+    pub gitea_api_url: Option<String>,
+    /// Token used to access the Gitea instance.
+    pub gitea_token: Option<String>,
+    /// User name for the HTTPS remotes accessed over git only.
+    pub git_username: Option<String>,
+    /// Password for the HTTPS remotes accessed over git only.
+    pub git_password: Option<String>,
+}
+
+impl HostSettings {
+    /// Loads the settings from the environment variables starting with
+    /// `prefix`, e.g. `{prefix}_WEB_URL` or `{prefix}_GIT_PASSWORD`.
+    pub fn from_env(prefix: &str) -> Self {
+        let var = |key: &str| env::var(format!("{prefix}_{key}")).ok();
+        Self {
+            web_url: var("WEB_URL"),
+            api_url: var("API_URL"),
+            github_token: var("GITHUB_TOKEN"),
+            gitlab_url: var("GITLAB_URL"),
+            gitlab_api_url: var("GITLAB_API_URL"),
+            gitlab_token: var("GITLAB_TOKEN"),
+            gitea_url: var("GITEA_URL"),
+            gitea_api_url: var("GITEA_API_URL"),
+            gitea_token: var("GITEA_TOKEN"),
+            git_username: var("GIT_USERNAME"),
+            git_password: var("GIT_PASSWORD"),
+        }
+    }
+
+    /// Returns the `value` of a setting, unless it is empty.
+    pub fn non_empty(value: &Option<String>) -> Option<&str> {
+        value.as_deref().filter(|value| !value.is_empty())
+    }
+}
+
+/// A repository on a code forge, such as GitHub, GitLab or Gitea.
+#[async_trait]
+pub trait Forge: RepoHandlerExt {
+    /// Returns the URL to clone the repository over HTTPS.
+    fn clone_url(&self) -> Result<Url>;
+
+    /// Returns the credentials used to fetch from or push to the repository,
+    /// or `None` to access it anonymously.
+    fn credentials(&self) -> Result<Option<Credentials>>;
+
+    /// Returns the web URL to compare the `head` revision against the `base`
+    /// revision, if the forge has one.
+    fn compare_url(&self, base: &str, head: &str) -> Option<Url>;
+
+    /// Returns the web URL of the `branch`, if the forge has one.
+    fn branch_url(&self, branch: &str) -> Option<Url>;
+
+    /// Opens the pull request (or merge request), or updates the one already
+    /// open for the same head branch, and returns its web URL.
+    async fn open_pull_request(&self, request: &PullRequest) -> Result<Url>;
+
+    /// Creates a release named `name` for the `tag`, which is created from
+    /// the `target` branch if it does not exist yet, and returns its web URL.
+    async fn create_release(&self, tag: &str, target: &str, name: &str, body: &str) -> Result<Url>;
+
+    /// Returns the open issue opened by tags-sync with the given `title`, if
+    /// any.
+    async fn find_open_issue(&self, title: &str) -> Result<Option<Issue>>;
+
+    /// Opens an issue labeled with [`ISSUE_LABEL`] if the forge supports it.
+    async fn open_issue(&self, title: &str, body: &str) -> Result<Issue>;
+
+    /// Adds a comment with the given `body` to the `issue`.
+    async fn comment_issue(&self, issue: &Issue, body: &str) -> Result<()>;
+
+    /// Closes the `issue`.
+    async fn close_issue(&self, issue: &Issue) -> Result<()>;
+
+    /// Sets a successful commit status on the commit `sha`, telling how the
+    /// `patch` was applied as returned by [`commit_status`], with a link to the
+    /// `target_url` if any.
+    async fn set_commit_status(
+        &self,
+        sha: &str,
+        patch: Option<PatchStatus>,
+        target_url: Option<&str>,
+    ) -> Result<()>;
+
+    /// Deletes the `branch` of the repository.
+    async fn delete_branch(&self, branch: &str) -> Result<()>;
+}
+
+/// Label of the issues opened by tags-sync.
+pub const ISSUE_LABEL: &str = "tags-sync";
+
+/// Prefix of the name of the commit statuses set by tags-sync on the synced
+/// branches.
+pub const STATUS_CONTEXT: &str = "tags-sync/patch";
+
+/// Returns the name and the description of the commit status telling how the
+/// `patch` was applied, or that it was skipped if `None`.
+///
+/// The outcome is part of the name, so that branch protection can require a
+/// clean patch while the statuses stay successful.
+pub fn commit_status(patch: Option<PatchStatus>) -> (String, &'static str) {
+    let (outcome, description) = match patch {
+        Some(PatchStatus::Clean) => ("clean", "Patch applied cleanly"),
+        Some(PatchStatus::ThreeWay) => ("three-way", "Patch applied with a three-way merge"),
+        None => ("skipped", "Patch skipped"),
+    };
+    (format!("{STATUS_CONTEXT}: {outcome}"), description)
+}
+
+/// An issue of a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// Number of the issue, which is the `iid` on GitLab.
+    pub number: u64,
+    /// Web URL of the issue.
+    pub url: Url,
+}
+
+/// Specification of a repository in the `[forge:]path` format, or a git URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepoSpec {
+    /// `owner/repo` or `github:owner/repo`.
+    GitHub { owner: String, name: String },
+    /// `gitlab:group/subgroup/project`, where the groups can be nested.
+    GitLab { path: String },
+    /// `gitea:owner/repo` or `forgejo:owner/repo`.
+    Gitea { owner: String, name: String },
+    /// `https://…`, `ssh://…` or `file://…`, which is accessed over git only.
+    Git { url: Url },
+}
+
+impl FromStr for RepoSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if value.contains("://") {
+            let url = Url::parse(value).context(format!("'{value}' is not a valid git URL."))?;
+            return match url.scheme() {
+                "https" | "http" | "ssh" | "git" | "file" => Ok(Self::Git { url }),
+                scheme => bail!("'{value}' has an unsupported scheme '{scheme}'."),
+            };
+        }
+        let (forge, path) = value.split_once(':').unwrap_or(("github", value));
+        let segments = path.split('/').collect::<Vec<_>>();
+        if segments.iter().any(|segment| segment.is_empty()) {
+            bail!("'{value}' contains an empty path segment.");
+        }
+        match forge {
+            "github" => match segments[..] {
+                [owner, name] => Ok(Self::GitHub {
+                    owner: owner.to_string(),
+                    name: name.to_string(),
+                }),
+                _ => bail!("'{value}' must be in format 'owner/repo'."),
+            },
+            "gitlab" if segments.len() >= 2 => Ok(Self::GitLab {
+                path: path.to_string(),
+            }),
+            "gitlab" => bail!("'{value}' must be in format 'gitlab:group/project'."),
+            "gitea" | "forgejo" => match segments[..] {
+                [owner, name] => Ok(Self::Gitea {
+                    owner: owner.to_string(),
+                    name: name.to_string(),
+                }),
+                _ => bail!("'{value}' must be in format '{forge}:owner/repo'."),
+            },
+            _ => bail!(
+                "'{value}' has an unknown forge '{forge}', expected 'github', 'gitlab', 'gitea' \
+                 or 'forgejo'."
+            ),
+        }
+    }
+}
+
+impl Display for RepoSpec {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::GitHub { owner, name } => write!(fmt, "{owner}/{name}"),
+            Self::GitLab { path } => write!(fmt, "gitlab:{path}"),
+            Self::Gitea { owner, name } => write!(fmt, "gitea:{owner}/{name}"),
+            Self::Git { url } => write!(fmt, "{url}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(parse_repo_spec {
+        assert_eq!("torvalds/linux".parse::<RepoSpec>()?, RepoSpec::GitHub {
+            owner: "torvalds".to_string(),
+            name: "linux".to_string(),
+        });
+        assert_eq!("github:torvalds/linux".parse::<RepoSpec>()?.to_string(), "torvalds/linux");
+        assert_eq!("gitlab:group/subgroup/project".parse::<RepoSpec>()?, RepoSpec::GitLab {
+            path: "group/subgroup/project".to_string(),
+        });
+
+        // Nested groups are only allowed on GitLab
+        assert!("group/subgroup/project".parse::<RepoSpec>().is_err());
+        assert!("gitlab:project".parse::<RepoSpec>().is_err());
+        assert!("gitlab:group//project".parse::<RepoSpec>().is_err());
+        // Forgejo keeps the same API as Gitea
+        assert_eq!("forgejo:owner/repo".parse::<RepoSpec>()?.to_string(), "gitea:owner/repo");
+        assert!("gitea:group/owner/repo".parse::<RepoSpec>().is_err());
+        assert!("bitbucket:owner/repo".parse::<RepoSpec>().is_err());
+
+        // Any git URL is accessed over git only
+        assert_eq!(
+            "ssh://git@example.com/linux.git".parse::<RepoSpec>()?,
+            RepoSpec::Git { url: Url::parse("ssh://git@example.com/linux.git")? }
+        );
+        assert!(matches!("file:///srv/git/linux.git".parse()?, RepoSpec::Git { .. }));
+        assert!("ftp://example.com/linux.git".parse::<RepoSpec>().is_err());
+    });
+
+    test_fn!(commit_status_names {
+        assert_eq!(commit_status(Some(PatchStatus::Clean)).0, "tags-sync/patch: clean");
+        assert_eq!(
+            commit_status(Some(PatchStatus::ThreeWay)),
+            ("tags-sync/patch: three-way".to_string(), "Patch applied with a three-way merge")
+        );
+        assert_eq!(commit_status(None).0, "tags-sync/patch: skipped");
+    });
+}
@@ -1,26 +1,37 @@
-use std::env;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    fmt::{Display, Formatter},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use git2::{
-    ApplyLocation, AutotagOption, Cred, Diff, FetchOptions, ProxyOptions, PushOptions,
-    RemoteCallbacks, Repository,
+    build::{CheckoutBuilder, TreeUpdateBuilder},
+    ApplyLocation, ApplyOptions, AutotagOption, Cred, CredentialType, Delta, Diff, ErrorCode,
+    FetchOptions, FetchPrune, Index, Oid, ProxyOptions, PushOptions, RemoteCallbacks, Repository,
+    ResetType,
 };
 use log::{debug, log_enabled, Level::Debug};
+use serde::Serialize;
 
 use crate::{
     consts::*,
-    utils::{github_token, CommitInfo},
+    utils::{retry, CommitInfo, Credentials, SyncError},
 };
 
 pub trait RepoExt {
-    fn fetch_upstream_tags(&self, tags: &[&str]) -> Result<()>;
-    fn checkout_tag(&self, tag: &str) -> Result<()>;
-    fn apply_patch(&self, diff: &Diff<'_>, commit_info: CommitInfo) -> Result<()>;
-    fn push_head(&self) -> Result<()>;
+    fn fetch_upstream_tags(&self, tags: &[&str], credentials: Option<&Credentials>) -> Result<()>;
+    fn fetch_origin_branches(&self, credentials: Option<&Credentials>) -> Result<()>;
+    fn checkout_tag(&self, tag: &str, branch: &str) -> Result<()>;
+    fn apply_patch(&self, patch: &PatchFile, commit_info: CommitInfo) -> Result<PatchStatus>;
+    fn push_head(&self, credentials: Option<&Credentials>) -> Result<()>;
+    fn reuse_origin_branch(&self, branch: &str) -> Result<Option<Oid>>;
 }
 
 impl RepoExt for Repository {
-    fn fetch_upstream_tags(&self, tags: &[&str]) -> Result<()> {
+    fn fetch_upstream_tags(&self, tags: &[&str], credentials: Option<&Credentials>) -> Result<()> {
         // Fetch only specified tags from upstream
         let refspecs = tags
             .iter()
@@ -29,23 +40,51 @@ impl RepoExt for Repository {
 
         debug!("Fetching refspecs: {}", refspecs.join(" "));
 
-        Ok(self.find_remote(UPSTREAM)?.fetch(
-            &refspecs,
-            Some(FetchOptions::new().download_tags(AutotagOption::None)),
-            None,
-        )?)
+        retry("Fetch upstream tags", || {
+            // Using the credentials of the base repository, in case it is private
+            Ok(self.find_remote(UPSTREAM)?.fetch(
+                &refspecs,
+                Some(
+                    FetchOptions::new()
+                        .download_tags(AutotagOption::None)
+                        .remote_callbacks(remote_callbacks(credentials)),
+                ),
+                None,
+            )?)
+        })
+        .map_err(|error| SyncError::git(error).into())
+    }
+
+    fn fetch_origin_branches(&self, credentials: Option<&Credentials>) -> Result<()> {
+        // The branches deleted from the origin are pruned, so that they are
+        // not mistaken for ones pushed by an earlier run
+        retry("Fetch origin branches", || {
+            Ok(self.find_remote(ORIGIN)?.fetch(
+                &[format!("+refs/heads/*:refs/remotes/{ORIGIN}/*")],
+                Some(
+                    FetchOptions::new()
+                        .download_tags(AutotagOption::None)
+                        .prune(FetchPrune::On)
+                        .remote_callbacks(remote_callbacks(credentials)),
+                ),
+                None,
+            )?)
+        })
+        .map_err(|error| SyncError::git(error).into())
     }
 
-    fn checkout_tag(&self, tag: &str) -> Result<()> {
+    fn checkout_tag(&self, tag: &str, branch_name: &str) -> Result<()> {
         let tag_commit = self
             .find_reference(&format!("refs/tags/{SYNC_PREFIX}{tag}"))?
             .peel_to_commit()?;
 
         debug!("Tag '{tag}' commit '{}'", tag_commit.id());
 
-        let branch_name = format!("{SYNC_PREFIX}{tag}");
+        // The branch may be left in the clone by a dry run or a failed push, so
+        // it is reset to the tag, which cannot be done while it is checked out
+        self.set_head_detached(tag_commit.id())?;
         let branch_ref = self
-            .branch(&branch_name, &tag_commit, false)?
+            .branch(branch_name, &tag_commit, true)?
             .into_reference();
         let branch_ref_name = branch_ref
             .name()
@@ -68,8 +107,30 @@ impl RepoExt for Repository {
         Ok(())
     }
 
-    fn apply_patch(&self, diff: &Diff<'_>, commit_info: CommitInfo) -> Result<()> {
-        self.apply(diff, ApplyLocation::Both, None)?;
+    fn apply_patch(&self, patch: &PatchFile, commit_info: CommitInfo) -> Result<PatchStatus> {
+        let diff = &patch.diff;
+        let status = match self.apply(diff, ApplyLocation::Both, None) {
+            Ok(()) => PatchStatus::Clean,
+            Err(error) => {
+                // Fall back to a three-way merge, like `git apply --3way` does
+                let mut merged = match three_way_merge(self, patch)? {
+                    Some(merged) if !merged.has_conflicts() => merged,
+                    _ => {
+                        return Err(SyncError::PatchConflict(PatchConflict {
+                            files: conflicting_files(self, diff)?,
+                            source: error,
+                        })
+                        .into())
+                    }
+                };
+                let tree = self.find_tree(merged.write_tree_to(self)?)?;
+                let mut index = self.index()?;
+                index.read_tree(&tree)?;
+                index.write()?;
+                self.checkout_index(Some(&mut index), Some(CheckoutBuilder::new().force()))?;
+                PatchStatus::ThreeWay
+            }
+        };
 
         let (author, committer, message) = commit_info;
         let tree_id = self.index()?.write_tree()?;
@@ -83,27 +144,21 @@ impl RepoExt for Repository {
             &parent_commit,
         ])?;
 
-        Ok(())
+        Ok(status)
     }
 
-    fn push_head(&self) -> Result<()> {
-        let mut callbacks = RemoteCallbacks::new();
-        // Using github token
-        callbacks.credentials(|_, _, _| {
-            let user = env::var("GITHUB_ACTOR")
-                .context("Cannot get GITHUB_ACTOR in environment")
-                .unwrap();
-            let token = github_token()
-                .context("Cannot get GITHUB_TOKEN in environment")
-                .unwrap();
-            Cred::userpass_plaintext(&user, &token)
-        });
+    fn push_head(&self, credentials: Option<&Credentials>) -> Result<()> {
+        // A rejected update, e.g. of a branch which is not a fast-forward, is
+        // only reported through its status
+        let rejected = RefCell::new(None);
+        let mut callbacks = remote_callbacks(credentials);
         callbacks.push_update_reference(|reference, status| {
             debug!(
                 "Pushed reference='{}', succeed='{}'",
                 reference,
                 status.is_none()
             );
+            *rejected.borrow_mut() = status.map(str::to_string);
             Ok(())
         });
 
@@ -116,13 +171,192 @@ impl RepoExt for Repository {
         // Push all changes from the current branch to the origin
         let head_ref = self.head()?;
         let head_ref_name = head_ref.name().unwrap();
-        self.find_remote(ORIGIN)?
-            .push(&[head_ref_name], Some(&mut options))?;
+        retry("Push head", || {
+            Ok(self
+                .find_remote(ORIGIN)?
+                .push(&[head_ref_name], Some(&mut options))?)
+        })
+        .map_err(SyncError::git)?;
+        drop(options);
+
+        match rejected.into_inner() {
+            Some(status) => {
+                Err(SyncError::Git(anyhow!("The origin rejected {head_ref_name}: {status}")).into())
+            }
+            None => Ok(()),
+        }
+    }
 
-        Ok(())
+    /// Resets the current branch to the same `branch` of the origin if it
+    /// has the same content, which is the same tree on top of the same
+    /// parents, and returns its commit.
+    ///
+    /// This is the case when an earlier run pushed the branch but was
+    /// interrupted before finishing, as the patch commit only differs by its
+    /// date.
+    fn reuse_origin_branch(&self, branch: &str) -> Result<Option<Oid>> {
+        let origin_ref = format!("refs/remotes/{ORIGIN}/{branch}");
+        let origin_commit = match self.find_reference(&origin_ref) {
+            Ok(reference) => reference.peel_to_commit()?,
+            Err(error) if error.code() == ErrorCode::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let head_commit = self.head()?.peel_to_commit()?;
+        if origin_commit.id() != head_commit.id()
+            && (origin_commit.tree_id() != head_commit.tree_id()
+                || !origin_commit.parent_ids().eq(head_commit.parent_ids()))
+        {
+            debug!(
+                "Branch '{branch}' of the origin is at '{}' with another content",
+                origin_commit.id()
+            );
+            return Ok(None);
+        }
+
+        self.reset(origin_commit.as_object(), ResetType::Hard, None)?;
+        Ok(Some(origin_commit.id()))
     }
 }
 
+/// A patch file parsed as a diff.
+pub struct PatchFile {
+    diff: Diff<'static>,
+    /// Abbreviated ids of the preimages as written in the `index` lines of
+    /// the patch, keyed by their ids padded with zeros as parsed in the diff.
+    abbreviated_ids: HashMap<Oid, String>,
+}
+
+impl PatchFile {
+    pub fn parse(patch: &[u8]) -> Result<Self> {
+        let diff = Diff::from_buffer(patch)?;
+        let abbreviated_ids = String::from_utf8_lossy(patch)
+            .lines()
+            .filter_map(|line| line.strip_prefix("index ")?.split_once(".."))
+            .filter_map(|(old, _)| Some((Oid::from_str(old).ok()?, old.to_string())))
+            .collect();
+        Ok(Self {
+            diff,
+            abbreviated_ids,
+        })
+    }
+}
+
+/// How a patch was applied to the synced branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchStatus {
+    /// The patch applied cleanly.
+    Clean,
+    /// The patch only applied with a three-way merge.
+    ThreeWay,
+}
+
+/// Error of a patch that does not apply to the synced branch.
+#[derive(Debug)]
+pub struct PatchConflict {
+    /// Paths of the files that the patch does not apply to.
+    pub files: Vec<String>,
+    source: git2::Error,
+}
+
+impl Display for PatchConflict {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self.files.is_empty() {
+            true => write!(fmt, "Patch does not apply"),
+            false => write!(fmt, "Patch does not apply to {}", self.files.join(", ")),
+        }
+    }
+}
+
+impl Error for PatchConflict {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Merges the `patch` into `HEAD` with a three-way merge, whose ancestor has the
+/// preimages of the patched files recorded by the `index` lines of the patch.
+///
+/// Returns `None` if any preimage is not in the repository.
+fn three_way_merge(repo: &Repository, patch: &PatchFile) -> Result<Option<Index>> {
+    let diff = &patch.diff;
+    let head = repo.head()?.peel_to_tree()?;
+    let mut ancestor = TreeUpdateBuilder::new();
+    for delta in diff.deltas() {
+        let old_file = delta.old_file();
+        let path = match old_file.path().or_else(|| delta.new_file().path()) {
+            Some(path) => path,
+            None => continue,
+        };
+        if delta.status() == Delta::Added {
+            ancestor.remove(path);
+            continue;
+        }
+        let abbreviated = match patch.abbreviated_ids.get(&old_file.id()) {
+            Some(abbreviated) if !old_file.id().is_zero() => abbreviated,
+            _ => return Ok(None),
+        };
+        match find_abbreviated(repo, abbreviated)? {
+            Some(id) => ancestor.upsert(path, id, old_file.mode()),
+            None => return Ok(None),
+        };
+    }
+    let ancestor = repo.find_tree(ancestor.create_updated(repo, &head)?)?;
+    let theirs = match repo.apply_to_tree(&ancestor, diff, None) {
+        Ok(mut theirs) => repo.find_tree(theirs.write_tree_to(repo)?)?,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(repo.merge_trees(&ancestor, &head, &theirs, None)?))
+}
+
+/// Returns the object of the repository whose id starts with the `abbreviated`
+/// id of an `index` line of a patch, if any.
+///
+/// The whole abbreviation is matched, as the id parsed from it is padded with
+/// zeros which cannot be told apart from the zeros it may end with.
+fn find_abbreviated(repo: &Repository, abbreviated: &str) -> Result<Option<Oid>> {
+    let id = Oid::from_str(abbreviated)?;
+    Ok(repo.odb()?.exists_prefix(id, abbreviated.len()).ok())
+}
+
+/// Returns the paths of the files that the `diff` does not apply to, by
+/// applying each file to the tree of `HEAD` on its own.
+fn conflicting_files(repo: &Repository, diff: &Diff<'_>) -> Result<Vec<String>> {
+    let tree = repo.head()?.peel_to_tree()?;
+    let mut files = Vec::new();
+    for delta in diff.deltas() {
+        let path = match delta.new_file().path().or_else(|| delta.old_file().path()) {
+            Some(path) => path.to_path_buf(),
+            None => continue,
+        };
+        let mut options = ApplyOptions::new();
+        options.delta_callback(|other| {
+            other.and_then(|other| other.new_file().path().or_else(|| other.old_file().path()))
+                == Some(path.as_path())
+        });
+        if repo.apply_to_tree(&tree, diff, Some(&mut options)).is_err() {
+            files.push(path.display().to_string());
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the callbacks authenticating to a remote with the given
+/// `credentials` over HTTPS, or with the SSH agent over SSH.
+pub fn remote_callbacks(credentials: Option<&Credentials>) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_, username, allowed| match credentials {
+        Some((user, password)) if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) => {
+            Cred::userpass_plaintext(user, password)
+        }
+        _ if allowed.contains(CredentialType::SSH_KEY) => {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        }
+        _ => Cred::default(),
+    });
+    callbacks
+}
+
 pub fn proxy_auto<'a>() -> ProxyOptions<'a> {
     let mut proxy = ProxyOptions::new();
     proxy.auto();
@@ -131,7 +365,7 @@ pub fn proxy_auto<'a>() -> ProxyOptions<'a> {
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, io::prelude::*, time::SystemTime};
+    use std::{env, fs::File, io::prelude::*, time::SystemTime};
 
     use git2::*;
     use log::info;
@@ -150,7 +384,7 @@ mod tests {
         assert!(repo.path().exists());
 
         repo.remote(UPSTREAM, "https://github.com/rust-lang/rustlings.git")?;
-        repo.fetch_upstream_tags(&[EXPECTED_TAG])?;
+        repo.fetch_upstream_tags(&[EXPECTED_TAG], None)?;
 
         // Make sure the tag have been fetched
         assert!(repo
@@ -158,12 +392,12 @@ mod tests {
             .is_ok());
 
         // Checkout the tag as a new branch
-        repo.checkout_tag(EXPECTED_TAG)?;
+        repo.checkout_tag(EXPECTED_TAG, &format!("sync-{EXPECTED_TAG}"))?;
 
         // Make sure the branch have been switched
         assert_eq!(
             repo.head()?.name(),
-            Some(format!("refs/heads/{SYNC_PREFIX}{EXPECTED_TAG}").as_str())
+            Some(format!("refs/heads/sync-{EXPECTED_TAG}").as_str())
         );
     });
 
@@ -208,6 +442,166 @@ mod tests {
         )?;
 
         // Push changes
-        repo.push_head()?;
+        let token = env::var("GITHUB_TOKEN")?;
+        repo.push_head(Some(&("x-access-token".to_string(), token)))?;
+    });
+
+    test_fn!(patch_conflict {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path())?;
+        std::fs::write(temp_dir.path().join("a.txt"), "one\n")?;
+        std::fs::write(temp_dir.path().join("b.txt"), "two\n")?;
+        let mut index = repo.index()?;
+        index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = Signature::now("tags-sync", "tags-sync@example.com")?;
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])?;
+
+        // Only the change of `b.txt` is based on different content
+        let patch = PatchFile::parse(
+            b"diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1 @@
+-one
++1
+diff --git a/b.txt b/b.txt
+--- a/b.txt
++++ b/b.txt
+@@ -1 +1 @@
+-zwei
++2
+",
+        )?;
+        let error = repo
+            .apply_patch(&patch, (signature.clone(), signature, "patch".to_string()))
+            .unwrap_err();
+        let Some(SyncError::PatchConflict(conflict)) = error.downcast_ref::<SyncError>() else {
+            panic!("{error:#} is not a patch conflict");
+        };
+        assert_eq!(conflict.files, ["b.txt"]);
+        assert_eq!(conflict.to_string(), "Patch does not apply to b.txt");
+    });
+
+    test_fn!(three_way_patch {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path())?;
+        let signature = Signature::now("tags-sync", "tags-sync@example.com")?;
+        let commit = |content: &str| -> Result<Oid> {
+            std::fs::write(temp_dir.path().join("a.txt"), content)?;
+            let mut index = repo.index()?;
+            index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+            index.write()?;
+            let tree = repo.find_tree(index.write_tree()?)?;
+            let parents = match repo.head() {
+                Ok(head) => vec![head.peel_to_commit()?],
+                Err(_) => vec![],
+            };
+            let parents = parents.iter().collect::<Vec<_>>();
+            Ok(repo.commit(Some("HEAD"), &signature, &signature, "commit", &tree, &parents)?)
+        };
+        commit("one\ntwo\nthree\n")?;
+        let preimage = repo.blob(b"one\ntwo\nthree\n")?.to_string();
+        commit("uno\ntwo\nthree\n")?;
+
+        // The context of the patch no longer matches the first line
+        let patch = PatchFile::parse(
+            format!(
+                "diff --git a/a.txt b/a.txt
+index {}..0123456 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+ one
+ two
+-three
++3
+",
+                &preimage[..7]
+            )
+            .as_bytes(),
+        )?;
+        let status = repo.apply_patch(&patch, (signature.clone(), signature.clone(), "patch".to_string()))?;
+        assert_eq!(status, PatchStatus::ThreeWay);
+        assert_eq!(std::fs::read_to_string(temp_dir.path().join("a.txt"))?, "uno\ntwo\n3\n");
+        assert!(repo.statuses(None)?.is_empty());
+    });
+
+    test_fn!(find_abbreviated_id {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path())?;
+        // A blob whose abbreviated id ends with a zero, which is lost once
+        // padded with zeros
+        let (id, hex) = (0..)
+            .map(|i| -> Result<_> {
+                let id = repo.blob(format!("{i}\n").as_bytes())?;
+                Ok((id, id.to_string()))
+            })
+            .find(|blob| matches!(blob, Ok((_, hex)) if hex[3..5].starts_with('0') && !hex[3..5].ends_with('0')))
+            .unwrap()?;
+        assert_eq!(find_abbreviated(&repo, &hex[..4])?, Some(id));
+        // The zeros of a longer abbreviation must match too
+        assert_eq!(find_abbreviated(&repo, &format!("{}0", &hex[..4]))?, None);
+        assert_eq!(find_abbreviated(&repo, "ffff")?, None);
+    });
+
+    test_fn!(reuse_origin_branch {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path())?;
+        let signature = Signature::now("tags-sync", "tags-sync@example.com")?;
+        let commit = |content: &str, message: &str, update_ref: Option<&str>| -> Result<Oid> {
+            std::fs::write(temp_dir.path().join("a.txt"), content)?;
+            let mut index = repo.index()?;
+            index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+            index.write()?;
+            let tree = repo.find_tree(index.write_tree()?)?;
+            let parents = match repo.head() {
+                Ok(head) => vec![head.peel_to_commit()?],
+                Err(_) => vec![],
+            };
+            let parents = parents.iter().collect::<Vec<_>>();
+            Ok(repo.commit(update_ref, &signature, &signature, message, &tree, &parents)?)
+        };
+        commit("one\n", "tag", Some("HEAD"))?;
+        let branch = "sync-v1.0";
+        assert_eq!(repo.reuse_origin_branch(branch)?, None);
+
+        // The patch of an earlier run is another commit with the same content
+        let pushed = commit("1\n", "patch of an earlier run", None)?;
+        repo.reference(&format!("refs/remotes/{ORIGIN}/{branch}"), pushed, true, "")?;
+        let patched = commit("1\n", "patch", Some("HEAD"))?;
+        assert_ne!(patched, pushed);
+        assert_eq!(repo.reuse_origin_branch(branch)?, Some(pushed));
+        assert_eq!(repo.head()?.target(), Some(pushed));
+
+        // A branch with another content is left to the push, which rejects it
+        commit("2\n", "another patch", Some("HEAD"))?;
+        assert_eq!(repo.reuse_origin_branch(branch)?, None);
+        assert_ne!(repo.head()?.target(), Some(pushed));
+    });
+
+    test_fn!(reject_push_of_other_content {
+        let origin_dir = tempdir()?;
+        Repository::init_bare(origin_dir.path())?;
+        let clone = |dir: &tempfile::TempDir| {
+            Repository::clone(origin_dir.path().to_str().unwrap(), dir.path())
+        };
+        let (first_dir, second_dir) = (tempdir()?, tempdir()?);
+        let (first, second) = (clone(&first_dir)?, clone(&second_dir)?);
+        let signature = Signature::now("tags-sync", "tags-sync@example.com")?;
+        let commit = |repo: &Repository, dir: &tempfile::TempDir, content: &str| -> Result<Oid> {
+            std::fs::write(dir.path().join("a.txt"), content)?;
+            let mut index = repo.index()?;
+            index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+            index.write()?;
+            let tree = repo.find_tree(index.write_tree()?)?;
+            Ok(repo.commit(Some("HEAD"), &signature, &signature, "patch", &tree, &[])?)
+        };
+
+        commit(&first, &first_dir, "1\n")?;
+        first.push_head(None)?;
+        // The same branch with other content is not a fast-forward
+        commit(&second, &second_dir, "2\n")?;
+        assert!(second.push_head(None).is_err());
     });
 }
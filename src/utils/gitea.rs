@@ -0,0 +1,603 @@
+//! A Gitea backend of [`Forge`], which also works with Forgejo since it keeps
+//! the same API.
+
+use std::{
+    fmt,
+    fmt::{Debug, Formatter},
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use reqwest::{header::AUTHORIZATION, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+
+use crate::utils::{
+    commit_status, has_next_page, retry_request, Branch, Credentials, Forge, HostSettings, Issue,
+    PatchStatus, PullRequest, RepoHandlerExt, SyncError, Tag, TagAnnotation, ISSUE_LABEL,
+};
+
+/// A Gitea or Forgejo instance.
+#[derive(Clone)]
+pub struct GiteaHost {
+    /// Base URL of the REST API, e.g. `https://codeberg.org/api/v1/`.
+    pub api_url: Url,
+    /// Base URL of the web pages, e.g. `https://codeberg.org/`.
+    pub web_url: Url,
+    /// Token used to access this instance.
+    token: Option<String>,
+}
+
+impl GiteaHost {
+    /// Builds the instance from the Gitea URLs and token of the `settings`.
+    ///
+    /// Unlike GitHub and GitLab, there is no canonical Gitea instance, so the
+    /// web URL is required.
+    pub fn new(settings: &HostSettings) -> Result<Self> {
+        fn parse_base_url(url: &str) -> Result<Url> {
+            let url = format!("{}/", url.trim_end_matches('/'));
+            Url::parse(&url).context(format!("Invalid URL: '{url}'"))
+        }
+
+        let web_url = parse_base_url(
+            HostSettings::non_empty(&settings.gitea_url).context("The Gitea URL is not set.")?,
+        )?;
+        let api_url = match HostSettings::non_empty(&settings.gitea_api_url) {
+            Some(api_url) => parse_base_url(api_url)?,
+            None => web_url.join("api/v1/")?,
+        };
+        Ok(Self {
+            api_url,
+            web_url,
+            token: HostSettings::non_empty(&settings.gitea_token).map(str::to_string),
+        })
+    }
+}
+
+impl Debug for GiteaHost {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        // Never print the token
+        fmt.debug_struct("GiteaHost")
+            .field("api_url", &self.api_url.as_str())
+            .field("web_url", &self.web_url.as_str())
+            .finish()
+    }
+}
+
+/// A repository on Gitea or Forgejo.
+#[derive(Debug, Clone)]
+pub struct GiteaRepo {
+    client: Client,
+    host: GiteaHost,
+    owner: String,
+    name: String,
+    /// URL to clone the repository over HTTPS, as returned by the lookup.
+    clone_url: Url,
+}
+
+impl GiteaRepo {
+    /// Looks up the `owner`/`name` repository on the given `host`, so that a
+    /// wrong spec or token fails early with a clear message.
+    pub async fn lookup(
+        host: GiteaHost,
+        owner: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Repository {
+            clone_url: Url,
+        }
+
+        let (owner, name) = (owner.into(), name.into());
+        let client = Client::new();
+        let response = send(
+            &client,
+            &host,
+            Method::GET,
+            &format!("repos/{owner}/{name}"),
+            |b| b,
+        )
+        .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(SyncError::Forge(anyhow!(
+                "Repository '{owner}/{name}' not found on {}, or the token cannot access it",
+                host.web_url
+            ))
+            .into());
+        }
+        let repository = response
+            .error_for_status()
+            .map_err(|error| SyncError::Forge(error.into()))?
+            .json::<Repository>()
+            .await?;
+        Ok(Self {
+            client,
+            host,
+            owner,
+            name,
+            clone_url: repository.clone_url,
+        })
+    }
+
+    /// Sends a request built by `build` to the given `route` of the
+    /// repository, and returns the successful response.
+    async fn send(
+        &self,
+        method: Method,
+        route: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response> {
+        let route = format!("repos/{}/{}/{route}", self.owner, self.name);
+        let response = send(&self.client, &self.host, method, &route, build).await?;
+        response
+            .error_for_status()
+            .map_err(|error| SyncError::Forge(error.into()).into())
+    }
+
+    /// Returns the pull request open for the `head` branch of the repository,
+    /// if any.
+    async fn find_open_pull(&self, head: &str) -> Result<Option<Pull>> {
+        let open_pulls = self.all_pages::<Pull>("pulls?state=open").await?;
+        Ok(open_pulls.into_iter().find(|pull| pull.head.branch == head))
+    }
+
+    /// Requests all pages of the given `route` of the repository and returns
+    /// their items.
+    async fn all_pages<T: DeserializeOwned>(&self, route: &str) -> Result<Vec<T>> {
+        let mut page = 1u32;
+        let mut items = Vec::new();
+        loop {
+            let response = self
+                .send(Method::GET, route, |builder| {
+                    builder.query(&[("limit", 50), ("page", page)])
+                })
+                .await?;
+            let link = response
+                .headers()
+                .get("link")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            items.extend(response.json::<Vec<T>>().await?);
+            if !has_next_page(&link) {
+                break;
+            }
+            page += 1;
+        }
+        Ok(items)
+    }
+}
+
+/// Sends a request to the `route` of the API on the `host`, and returns the
+/// response if it is not a server error. Only the idempotent requests are
+/// retried.
+async fn send(
+    client: &Client,
+    host: &GiteaHost,
+    method: Method,
+    route: &str,
+    build: impl Fn(RequestBuilder) -> RequestBuilder,
+) -> Result<Response> {
+    let url = host.api_url.join(route)?;
+    retry_request(&method, &format!("Request '{url}'"), || async {
+        let mut builder = client.request(method.clone(), url.clone());
+        if let Some(token) = &host.token {
+            builder = builder.header(AUTHORIZATION, format!("token {token}"));
+        }
+        let response = build(builder).send().await?;
+        if response.status().is_server_error() {
+            // Let the retry policy decide whether to try again
+            response.error_for_status_ref()?;
+        }
+        Ok(response)
+    })
+    .await
+    .map_err(|error| SyncError::request(error).into())
+}
+
+#[derive(Deserialize)]
+struct GiteaTag {
+    name: String,
+    /// SHA of the tag object for annotated tags, or the commit otherwise.
+    id: String,
+    message: Option<String>,
+    commit: GiteaTagCommit,
+}
+
+#[derive(Deserialize)]
+struct GiteaTagCommit {
+    sha: String,
+    created: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct GiteaBranch {
+    name: String,
+    protected: bool,
+    commit: GiteaBranchCommit,
+}
+
+#[derive(Deserialize)]
+struct GiteaBranchCommit {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct HtmlUrl {
+    html_url: Url,
+}
+
+#[derive(Deserialize)]
+struct Pull {
+    number: u64,
+    html_url: Url,
+    head: PullHead,
+}
+
+#[derive(Deserialize)]
+struct PullHead {
+    #[serde(rename = "ref")]
+    branch: String,
+}
+
+#[derive(Deserialize)]
+struct GiteaIssue {
+    number: u64,
+    html_url: Url,
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct Label {
+    id: u64,
+    name: String,
+}
+
+impl From<GiteaTag> for Tag {
+    fn from(tag: GiteaTag) -> Self {
+        let annotation = (tag.id != tag.commit.sha).then(|| TagAnnotation {
+            sha: tag.id,
+            message: tag.message.unwrap_or_default(),
+            tagger_date: None,
+        });
+        Self {
+            name: tag.name,
+            sha: tag.commit.sha,
+            commit_date: tag.commit.created,
+            annotation,
+        }
+    }
+}
+
+impl GiteaRepo {
+    /// Looks up the IDs of the labels with the given `names`, which are
+    /// required to label pull requests.
+    async fn label_ids(&self, names: &[String]) -> Result<Vec<u64>> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let labels = self.all_pages::<Label>("labels").await?;
+        Ok(names
+            .iter()
+            .filter_map(|name| {
+                let label = labels.iter().find(|label| label.name == *name);
+                if label.is_none() {
+                    warn!(
+                        "Label '{name}' not found in '{}/{}', skipping",
+                        self.owner, self.name
+                    );
+                }
+                label.map(|label| label.id)
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl RepoHandlerExt for GiteaRepo {
+    async fn list_all_tags(&self) -> Result<Vec<Tag>> {
+        let tags = self.all_pages::<GiteaTag>("tags").await?;
+        Ok(tags.into_iter().map(Tag::from).collect())
+    }
+
+    async fn list_all_branches(&self) -> Result<Vec<Branch>> {
+        let branches = self.all_pages::<GiteaBranch>("branches").await?;
+        Ok(branches
+            .into_iter()
+            .map(|branch| Branch {
+                name: branch.name,
+                sha: branch.commit.id,
+                protected: branch.protected,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Forge for GiteaRepo {
+    fn clone_url(&self) -> Result<Url> {
+        Ok(self.clone_url.clone())
+    }
+
+    /// Gitea takes the user name as the token if the password is
+    /// `x-oauth-basic`, so we do not need to know the owner of the token.
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        let credentials = |token: &String| (token.clone(), "x-oauth-basic".to_string());
+        Ok(self.host.token.as_ref().map(credentials))
+    }
+
+    fn compare_url(&self, base: &str, head: &str) -> Option<Url> {
+        let route = format!("{}/{}/compare/{base}...{head}", self.owner, self.name);
+        self.host.web_url.join(&route).ok()
+    }
+
+    fn branch_url(&self, branch: &str) -> Option<Url> {
+        let route = format!("{}/{}/src/branch/{branch}", self.owner, self.name);
+        self.host.web_url.join(&route).ok()
+    }
+
+    async fn open_pull_request(&self, request: &PullRequest) -> Result<Url> {
+        let body = json!({
+            "head": request.head,
+            "base": request.base,
+            "title": request.title,
+            "body": request.body,
+            "assignees": request.assignees,
+            "labels": self.label_ids(&request.labels).await?,
+        });
+        let pull = match self.find_open_pull(&request.head).await? {
+            Some(pull) => {
+                debug!(
+                    "Updating pull request #{} of '{}'",
+                    pull.number, request.head
+                );
+                let route = format!("pulls/{}", pull.number);
+                self.send(Method::PATCH, &route, |builder| builder.json(&body))
+                    .await?;
+                pull
+            }
+            None => match self
+                .send(Method::POST, "pulls", |builder| builder.json(&body))
+                .await
+            {
+                Ok(response) => response.json::<Pull>().await?,
+                // The pull request may have been opened even though the
+                // request failed, so it is looked up instead of opened again
+                Err(error) => self
+                    .find_open_pull(&request.head)
+                    .await
+                    .ok()
+                    .flatten()
+                    .ok_or(error)?,
+            },
+        };
+        if !request.reviewers.is_empty() {
+            let body = json!({ "reviewers": request.reviewers });
+            let route = format!("pulls/{}/requested_reviewers", pull.number);
+            self.send(Method::POST, &route, |builder| builder.json(&body))
+                .await?;
+        }
+        Ok(pull.html_url)
+    }
+
+    async fn create_release(&self, tag: &str, target: &str, name: &str, body: &str) -> Result<Url> {
+        let body = json!({
+            "tag_name": tag,
+            "target_commitish": target,
+            "name": name,
+            "body": body,
+        });
+        let release = match self
+            .send(Method::POST, "releases", |builder| builder.json(&body))
+            .await
+        {
+            Ok(response) => response.json::<HtmlUrl>().await?,
+            // The release may have been created even though the request
+            // failed, so it is looked up instead of created again
+            Err(error) => {
+                self.send(Method::GET, &format!("releases/tags/{tag}"), |builder| {
+                    builder
+                })
+                .await
+                .map_err(|_| error)?
+                .json::<HtmlUrl>()
+                .await?
+            }
+        };
+        Ok(release.html_url)
+    }
+
+    /// Labels on Gitea need to be created before use, so we only look up
+    /// the issues by title.
+    async fn find_open_issue(&self, title: &str) -> Result<Option<Issue>> {
+        let issues = self
+            .all_pages::<GiteaIssue>("issues?state=open&type=issues")
+            .await?;
+        Ok(issues
+            .into_iter()
+            .find(|issue| issue.title == title)
+            .map(|issue| Issue {
+                number: issue.number,
+                url: issue.html_url,
+            }))
+    }
+
+    async fn open_issue(&self, title: &str, body: &str) -> Result<Issue> {
+        let body = json!({
+            "title": title,
+            "body": body,
+            "labels": self.label_ids(&[ISSUE_LABEL.to_string()]).await?,
+        });
+        match self
+            .send(Method::POST, "issues", |builder| builder.json(&body))
+            .await
+        {
+            Ok(response) => {
+                let issue = response.json::<GiteaIssue>().await?;
+                Ok(Issue {
+                    number: issue.number,
+                    url: issue.html_url,
+                })
+            }
+            // The issue may have been opened even though the request failed,
+            // so it is looked up instead of opened again
+            Err(error) => self
+                .find_open_issue(title)
+                .await
+                .ok()
+                .flatten()
+                .ok_or(error),
+        }
+    }
+
+    async fn comment_issue(&self, issue: &Issue, body: &str) -> Result<()> {
+        let route = format!("issues/{}/comments", issue.number);
+        let body = json!({ "body": body });
+        self.send(Method::POST, &route, |builder| builder.json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn close_issue(&self, issue: &Issue) -> Result<()> {
+        let route = format!("issues/{}", issue.number);
+        let body = json!({ "state": "closed" });
+        self.send(Method::PATCH, &route, |builder| builder.json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn set_commit_status(
+        &self,
+        sha: &str,
+        patch: Option<PatchStatus>,
+        target_url: Option<&str>,
+    ) -> Result<()> {
+        let route = format!("statuses/{sha}");
+        let (name, description) = commit_status(patch);
+        let body = json!({
+            "state": "success",
+            "context": name,
+            "description": description,
+            "target_url": target_url,
+        });
+        self.send(Method::POST, &route, |builder| builder.json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_branch(&self, branch: &str) -> Result<()> {
+        let route = format!("branches/{branch}");
+        self.send(Method::DELETE, &route, |builder| builder).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_async_fn, utils::test::StubServer};
+
+    async fn stub_repo(server: &StubServer) -> Result<GiteaRepo> {
+        let host = GiteaHost {
+            api_url: server.url.join("api/v1/")?,
+            web_url: server.url.clone(),
+            token: Some("secret".to_string()),
+        };
+        GiteaRepo::lookup(host, "owner", "repo").await
+    }
+
+    test_async_fn!(list_all_tags {
+        let server = StubServer::start(vec![
+            (
+                "GET /api/v1/repos/owner/repo ",
+                "",
+                json!({ "clone_url": "https://gitea.example.com/owner/repo.git" }).to_string(),
+            ),
+            (
+                "GET /api/v1/repos/owner/repo/tags?limit=50&page=1 ",
+                "Link: <http://stub/tags?page=2>; rel=\"next\"\r\n",
+                json!([{
+                    "name": "v1.0",
+                    "id": "a",
+                    "message": "",
+                    "commit": { "sha": "a", "created": "2022-09-01T08:00:00+08:00" }
+                }])
+                .to_string(),
+            ),
+            (
+                "GET /api/v1/repos/owner/repo/tags?limit=50&page=2 ",
+                "",
+                json!([{
+                    "name": "v2.0",
+                    "id": "b",
+                    "message": "Release v2.0",
+                    "commit": { "sha": "c", "created": null }
+                }])
+                .to_string(),
+            ),
+        ])
+        .await?;
+        let repo = stub_repo(&server).await?;
+        let tags = repo.list_all_tags().await?;
+
+        assert_eq!(repo.clone_url()?.as_str(), "https://gitea.example.com/owner/repo.git");
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].sha, "a");
+        assert!(tags[0].annotation.is_none());
+        assert_eq!(tags[1].sha, "c");
+        assert_eq!(tags[1].annotation.as_ref().unwrap().sha, "b");
+        // Every request is authenticated with the token
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.contains("authorization: token secret")));
+    });
+
+    test_async_fn!(open_pull_request {
+        let server = StubServer::start(vec![
+            ("GET /api/v1/repos/owner/repo ", "", json!({ "clone_url": "https://gitea.example.com/owner/repo.git" }).to_string()),
+            (
+                "GET /api/v1/repos/owner/repo/pulls?state=open&limit=50&page=1 ",
+                "",
+                json!([{ "number": 1, "html_url": "https://gitea.example.com/owner/repo/pulls/1", "head": { "ref": "sync-v1.0" } }]).to_string(),
+            ),
+            ("GET /api/v1/repos/owner/repo/labels?limit=50&page=1 ", "", json!([{ "id": 7, "name": "sync" }]).to_string()),
+            ("PATCH /api/v1/repos/owner/repo/pulls/1 ", "", "{}".to_string()),
+            ("POST /api/v1/repos/owner/repo/pulls ", "", json!({ "number": 2, "html_url": "https://gitea.example.com/owner/repo/pulls/2", "head": { "ref": "sync-v2.0" } }).to_string()),
+        ])
+        .await?;
+        let repo = stub_repo(&server).await?;
+        let request = |head: &str| PullRequest {
+            head: head.to_string(),
+            base: "main".to_string(),
+            title: format!("Sync {head}"),
+            body: String::new(),
+            labels: vec!["sync".to_string(), "missing".to_string()],
+            assignees: vec![],
+            reviewers: vec![],
+        };
+
+        // The open pull request of the branch is updated rather than duplicated
+        let url = repo.open_pull_request(&request("sync-v1.0")).await?;
+        assert_eq!(url.as_str(), "https://gitea.example.com/owner/repo/pulls/1");
+        let requests = server.requests();
+        let request_line = requests.last().unwrap();
+        assert!(request_line.starts_with("PATCH /api/v1/repos/owner/repo/pulls/1 "));
+        assert!(request_line.contains(r#""labels":[7]"#));
+
+        let url = repo.open_pull_request(&request("sync-v2.0")).await?;
+        assert_eq!(url.as_str(), "https://gitea.example.com/owner/repo/pulls/2");
+        let requests = server.requests();
+        let request_line = requests.last().unwrap();
+        assert!(request_line.starts_with("POST /api/v1/repos/owner/repo/pulls "));
+        assert!(request_line.contains(r#""head":"sync-v2.0""#));
+    });
+
+    test_async_fn!(lookup_missing_repo {
+        let server = StubServer::start(vec![]).await?;
+        let error = stub_repo(&server).await.unwrap_err();
+        assert!(error.to_string().contains("'owner/repo' not found"));
+    });
+}
@@ -1,31 +1,187 @@
 //! A lightweight wrapper around the [`octocrab`] crate.
 
-use anyhow::Result;
+use std::{
+    fmt,
+    fmt::{Debug, Formatter},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use octocrab::{
-    models::repos::{Branch, Tag},
-    repos::RepoHandler,
-    Octocrab,
+use log::{debug, warn};
+use octocrab::{models::repos, Octocrab};
+use once_cell::sync::OnceCell;
+use reqwest::{
+    header::{HeaderMap, IF_NONE_MATCH},
+    Method, RequestBuilder, Response, StatusCode, Url,
 };
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 
-/// Requests all pages through a given `expression` and return their items.
+use crate::utils::{
+    commit_status, installation_token, retry_request, Branch, CachedResponse, Credentials, Forge,
+    GitHubApp, HostSettings, Issue, PatchStatus, PullRequest, ResponseCache, SyncError, Tag,
+    ISSUE_LABEL,
+};
+
+/// A repository on GitHub.
 ///
-/// We have been requesting from the first page until
-/// the result does not have the next page.
-macro_rules! all_page_items {
-    ($expression:expr) => {{
+/// Unlike [`octocrab::repos::RepoHandler`], the listings are requested through the raw REST
+/// API, so that we can take care of the rate limit through the response
+/// headers.
+pub struct GitHubRepo<'octo> {
+    api: &'octo Octocrab,
+    owner: String,
+    name: String,
+    host: GitHubHost,
+    /// ID of the GitHub App installation the `api` is authenticated as, if
+    /// any.
+    installation: Option<u64>,
+    cache: Option<ResponseCache>,
+}
+
+impl<'octo> GitHubRepo<'octo> {
+    pub fn new(api: &'octo Octocrab, owner: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            api,
+            owner: owner.into(),
+            name: name.into(),
+            host: GitHubHost::default(),
+            installation: None,
+            cache: None,
+        }
+    }
+
+    /// Sets the GitHub instance hosting the repository, and the GitHub App
+    /// installation its `api` is authenticated as, if any.
+    pub fn with_host(mut self, host: GitHubHost, installation: Option<u64>) -> Self {
+        self.host = host;
+        self.installation = installation;
+        self
+    }
+
+    /// Caches the listings in the given `cache`, so that the unchanged pages
+    /// are requested conditionally and do not count against the rate limit.
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn api(&self) -> &'octo Octocrab {
+        self.api
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Requests all pages of the given repository `route` and returns their
+    /// items.
+    ///
+    /// We have been requesting from the first page until the result does not
+    /// have the next page. Once the first page tells us how many pages there
+    /// are, we make sure the remaining rate limit can cover all of them.
+    async fn all_pages<T: DeserializeOwned>(&self, route: &str) -> Result<Vec<T>> {
         let mut page = 1u32;
         let mut items = Vec::new();
+        let mut budget_checked = false;
+        // The route may already have a query
+        let separator = if route.contains('?') { '&' } else { '?' };
         loop {
-            let page_items = $expression.page(page).per_page(100).send().await?;
-            items.extend(page_items.items);
-            if page_items.next.is_none() {
+            let page_route = format!(
+                "repos/{}/{}/{route}{separator}per_page=100&page={page}",
+                self.owner, self.name
+            );
+            let (response, cached) = self.request_page(&page_route).await?;
+            items.extend(serde_json::from_str::<Vec<T>>(&response.body)?);
+
+            // The pages found in the cache do not count against the rate
+            // limit, so the budget is only checked once a page misses it
+            let link = response.link.unwrap_or_default();
+            if !cached && !budget_checked {
+                if let Some(last_page) = last_page(&link) {
+                    ensure_rate_limit(self.api, last_page.saturating_sub(page)).await?;
+                }
+                budget_checked = true;
+            }
+            if !has_next_page(&link) {
                 break;
             }
             page += 1;
         }
         Ok(items)
-    }};
+    }
+
+    /// Returns the pull request open for the `head` branch of the repository,
+    /// if any.
+    async fn find_open_pull(&self, head: &str) -> Result<Option<PullInfo>> {
+        let route = format!("repos/{}/{}/pulls", self.owner, self.name);
+        let head = format!("{}:{head}", self.owner);
+        let open_pulls = send(self.api, Method::GET, &route, |builder| {
+            builder.query(&[("state", "open"), ("head", &head)])
+        })
+        .await?
+        .json::<Vec<PullInfo>>()
+        .await?;
+        Ok(open_pulls.into_iter().next())
+    }
+
+    /// Requests a page of the given `route`, conditionally if it has been
+    /// cached before, and returns it together with whether it was cached.
+    async fn request_page(&self, route: &str) -> Result<(CachedResponse, bool)> {
+        let key = self.cache_key(route);
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(&key));
+        let etag = cached.as_ref().and_then(|cached| cached.etag.as_deref());
+        let response = request(self.api, route, etag).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!("'{route}' is not modified, using the cached response");
+            let cached = cached.context(format!("'{route}' is not modified but not cached"))?;
+            return Ok((cached, true));
+        }
+
+        let header = |key: &str| {
+            response
+                .headers()
+                .get(key)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header("etag");
+        let link = header("link");
+        let response = CachedResponse {
+            etag,
+            link,
+            body: response.text().await?,
+        };
+        if let (Some(cache), Some(_)) = (&self.cache, &response.etag) {
+            cache.put(&key, &response)?;
+        }
+        Ok((response, false))
+    }
+
+    /// Returns the key of the `route` in the cache, which tells apart the
+    /// same route on another instance, or requested with another token.
+    ///
+    /// Only a digest of the token is part of the key, and the installation
+    /// tokens are told apart from the others as a whole, as they change on
+    /// every run.
+    fn cache_key(&self, route: &str) -> String {
+        // The digest must stay the same across builds, as the cache outlives them
+        let identity = match self.installation {
+            Some(installation_id) => Sha256::digest(format!("installation:{installation_id}")),
+            None => Sha256::digest(self.host.token().unwrap_or_default()),
+        };
+        let identity = identity[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        format!("{}{route}#{identity}", self.host.api_url)
+    }
 }
 
 #[async_trait]
@@ -38,16 +194,485 @@ pub trait RepoHandlerExt {
 }
 
 #[async_trait]
-impl RepoHandlerExt for RepoHandler<'_> {
+impl RepoHandlerExt for GitHubRepo<'_> {
     async fn list_all_tags(&self) -> Result<Vec<Tag>> {
-        all_page_items!(self.list_tags())
+        let tags = self.all_pages::<repos::Tag>("tags").await?;
+        Ok(tags.into_iter().map(Tag::from).collect())
     }
 
     async fn list_all_branches(&self) -> Result<Vec<Branch>> {
-        all_page_items!(self.list_branches())
+        let branches = self.all_pages::<repos::Branch>("branches").await?;
+        Ok(branches.into_iter().map(Branch::from).collect())
+    }
+}
+
+#[async_trait]
+impl Forge for GitHubRepo<'_> {
+    fn clone_url(&self) -> Result<Url> {
+        self.host.clone_url(&self.owner, &self.name)
+    }
+
+    /// GitHub accepts any user name along with a token, so we always use
+    /// the one required by the installation tokens.
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        let token = match self.installation.and_then(installation_token) {
+            Some(token) => token,
+            None => self.host.token()?,
+        };
+        Ok(Some(("x-access-token".to_string(), token)))
+    }
+
+    fn compare_url(&self, base: &str, head: &str) -> Option<Url> {
+        let route = format!("{}/{}/compare/{base}...{head}", self.owner, self.name);
+        self.host.web_url.join(&route).ok()
+    }
+
+    fn branch_url(&self, branch: &str) -> Option<Url> {
+        let route = format!("{}/{}/tree/{branch}", self.owner, self.name);
+        self.host.web_url.join(&route).ok()
+    }
+
+    async fn open_pull_request(&self, request: &PullRequest) -> Result<Url> {
+        let route = format!("repos/{}/{}/pulls", self.owner, self.name);
+        let body = json!({
+            "head": request.head,
+            "base": request.base,
+            "title": request.title,
+            "body": request.body,
+        });
+        let pull = match self.find_open_pull(&request.head).await? {
+            Some(pull) => {
+                debug!(
+                    "Updating pull request #{} of '{}'",
+                    pull.number, request.head
+                );
+                let route = format!("{route}/{}", pull.number);
+                send(self.api, Method::PATCH, &route, |builder| {
+                    builder.json(&body)
+                })
+                .await?;
+                pull
+            }
+            None => match send(self.api, Method::POST, &route, |builder| {
+                builder.json(&body)
+            })
+            .await
+            {
+                Ok(response) => response.json::<PullInfo>().await?,
+                // The pull request may have been opened even though the
+                // request failed, so it is looked up instead of opened again
+                Err(error) => self
+                    .find_open_pull(&request.head)
+                    .await
+                    .ok()
+                    .flatten()
+                    .ok_or(error)?,
+            },
+        };
+
+        // Pull requests share the labels and assignees with issues
+        let issue_route = format!("repos/{}/{}/issues/{}", self.owner, self.name, pull.number);
+        if !request.labels.is_empty() {
+            let body = json!({ "labels": request.labels });
+            send(
+                self.api,
+                Method::POST,
+                &format!("{issue_route}/labels"),
+                |builder| builder.json(&body),
+            )
+            .await?;
+        }
+        if !request.assignees.is_empty() {
+            let body = json!({ "assignees": request.assignees });
+            send(
+                self.api,
+                Method::POST,
+                &format!("{issue_route}/assignees"),
+                |builder| builder.json(&body),
+            )
+            .await?;
+        }
+        if !request.reviewers.is_empty() {
+            let body = json!({ "reviewers": request.reviewers });
+            let route = format!("{route}/{}/requested_reviewers", pull.number);
+            send(self.api, Method::POST, &route, |builder| {
+                builder.json(&body)
+            })
+            .await?;
+        }
+        Ok(pull.html_url)
+    }
+
+    async fn create_release(&self, tag: &str, target: &str, name: &str, body: &str) -> Result<Url> {
+        let route = format!("repos/{}/{}/releases", self.owner, self.name);
+        let body = json!({
+            "tag_name": tag,
+            "target_commitish": target,
+            "name": name,
+            "body": body,
+        });
+        let release = match send(self.api, Method::POST, &route, |builder| {
+            builder.json(&body)
+        })
+        .await
+        {
+            Ok(response) => response.json::<HtmlUrl>().await?,
+            // The release may have been created even though the request
+            // failed, so it is looked up instead of created again
+            Err(error) => {
+                send(
+                    self.api,
+                    Method::GET,
+                    &format!("{route}/tags/{tag}"),
+                    |builder| builder,
+                )
+                .await
+                .map_err(|_| error)?
+                .json::<HtmlUrl>()
+                .await?
+            }
+        };
+        Ok(release.html_url)
+    }
+
+    async fn find_open_issue(&self, title: &str) -> Result<Option<Issue>> {
+        let route = format!("issues?state=open&labels={ISSUE_LABEL}");
+        let issues = self.all_pages::<IssueInfo>(&route).await?;
+        Ok(issues
+            .into_iter()
+            .find(|issue| issue.title == title && issue.pull_request.is_none())
+            .map(|issue| Issue {
+                number: issue.number,
+                url: issue.html_url,
+            }))
+    }
+
+    async fn open_issue(&self, title: &str, body: &str) -> Result<Issue> {
+        let route = format!("repos/{}/{}/issues", self.owner, self.name);
+        let body = json!({ "title": title, "body": body, "labels": [ISSUE_LABEL] });
+        match send(self.api, Method::POST, &route, |builder| {
+            builder.json(&body)
+        })
+        .await
+        {
+            Ok(response) => {
+                let issue = response.json::<PullInfo>().await?;
+                Ok(Issue {
+                    number: issue.number,
+                    url: issue.html_url,
+                })
+            }
+            // The issue may have been opened even though the request failed,
+            // so it is looked up instead of opened again
+            Err(error) => self
+                .find_open_issue(title)
+                .await
+                .ok()
+                .flatten()
+                .ok_or(error),
+        }
+    }
+
+    async fn comment_issue(&self, issue: &Issue, body: &str) -> Result<()> {
+        let route = format!(
+            "repos/{}/{}/issues/{}/comments",
+            self.owner, self.name, issue.number
+        );
+        let body = json!({ "body": body });
+        send(self.api, Method::POST, &route, |builder| {
+            builder.json(&body)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn close_issue(&self, issue: &Issue) -> Result<()> {
+        let route = format!("repos/{}/{}/issues/{}", self.owner, self.name, issue.number);
+        let body = json!({ "state": "closed" });
+        send(self.api, Method::PATCH, &route, |builder| {
+            builder.json(&body)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_commit_status(
+        &self,
+        sha: &str,
+        patch: Option<PatchStatus>,
+        target_url: Option<&str>,
+    ) -> Result<()> {
+        let route = format!("repos/{}/{}/statuses/{sha}", self.owner, self.name);
+        let (name, description) = commit_status(patch);
+        let body = json!({
+            "state": "success",
+            "context": name,
+            "description": description,
+            "target_url": target_url,
+        });
+        send(self.api, Method::POST, &route, |builder| {
+            builder.json(&body)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_branch(&self, branch: &str) -> Result<()> {
+        let route = format!("repos/{}/{}/git/refs/heads/{branch}", self.owner, self.name);
+        send(self.api, Method::DELETE, &route, |builder| builder).await?;
+        Ok(())
     }
 }
 
+#[derive(Deserialize)]
+struct HtmlUrl {
+    html_url: Url,
+}
+
+#[derive(Deserialize)]
+struct PullInfo {
+    number: u64,
+    html_url: Url,
+}
+
+#[derive(Deserialize)]
+struct IssueInfo {
+    number: u64,
+    html_url: Url,
+    title: String,
+    /// Only present if the issue is a pull request.
+    pull_request: Option<serde_json::Value>,
+}
+
+/// Status of the rate limit read from the `X-RateLimit-*` response headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Maximum number of requests in the current window.
+    pub limit: u32,
+    /// Number of requests remaining in the current window.
+    pub remaining: u32,
+    /// Time at which the current window resets, in UTC epoch seconds.
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        fn header<T: std::str::FromStr>(headers: &HeaderMap, key: &str) -> Option<T> {
+            headers.get(key)?.to_str().ok()?.parse().ok()
+        }
+
+        Some(Self {
+            limit: header(headers, "x-ratelimit-limit")?,
+            remaining: header(headers, "x-ratelimit-remaining")?,
+            reset: header(headers, "x-ratelimit-reset")?,
+        })
+    }
+
+    /// Returns how long it is until the current window resets.
+    pub fn reset_in(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // One more second to make sure the window has been reset
+        Duration::from_secs(self.reset.saturating_sub(now) + 1)
+    }
+}
+
+/// Longest time we are willing to wait for the rate limit, which is set once
+/// by [`set_max_rate_limit_wait`].
+static MAX_RATE_LIMIT_WAIT: OnceCell<Duration> = OnceCell::new();
+
+/// Sets the longest time we are willing to wait for the rate limit, before any
+/// request is sent.
+pub fn set_max_rate_limit_wait(max_wait: Duration) {
+    let _ = MAX_RATE_LIMIT_WAIT.set(max_wait);
+}
+
+/// Returns the longest time we are willing to wait for the rate limit, which
+/// is 10 minutes unless set by [`set_max_rate_limit_wait`].
+fn max_rate_limit_wait() -> Duration {
+    MAX_RATE_LIMIT_WAIT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_secs(600))
+}
+
+/// Sends a `GET` request to the given API `route` and returns the successful
+/// response. If `etag` is given, the request is conditional and the response
+/// may be `304 Not Modified`.
+async fn request(api: &Octocrab, route: &str, etag: Option<&str>) -> Result<Response> {
+    send(api, Method::GET, route, |builder| match etag {
+        Some(etag) => builder.header(IF_NONE_MATCH, etag),
+        None => builder,
+    })
+    .await
+}
+
+/// Sends a request built by `build` to the given API `route` and returns the
+/// successful response. Only the idempotent requests are retried on
+/// transient errors.
+///
+/// When the primary or secondary rate limit is hit, we wait for as long as
+/// the `Retry-After` or `X-RateLimit-Reset` header tells us and then try
+/// again, unless that takes longer than [`max_rate_limit_wait`].
+pub(crate) async fn send(
+    api: &Octocrab,
+    method: Method,
+    route: &str,
+    build: impl Fn(RequestBuilder) -> RequestBuilder,
+) -> Result<Response> {
+    let url = api.absolute_url(route)?;
+    loop {
+        let response = retry_request(&method, &format!("Request '{route}'"), || async {
+            let builder = build(api.request_builder(url.clone(), method.clone()));
+            let response = api.execute(builder).await?;
+            // Let the server errors be retried
+            if response.status().is_server_error() {
+                response.error_for_status_ref()?;
+            }
+            Ok(response)
+        })
+        .await
+        .map_err(SyncError::request)?;
+
+        let status = response.status();
+        let rate_limit = RateLimit::from_headers(response.headers());
+        if let Some(rate_limit) = rate_limit {
+            debug!("Rate limit after '{route}': {rate_limit:?}");
+        }
+        if status.is_success() || status == StatusCode::NOT_MODIFIED {
+            return Ok(response);
+        }
+
+        let retry_after = response
+            .headers()
+            .get("retry-after")
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs);
+        let wait = match (status, retry_after, rate_limit) {
+            // Secondary rate limit
+            (StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS, Some(retry_after), _) => {
+                retry_after
+            }
+            // Primary rate limit
+            (StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS, None, Some(rate_limit))
+                if rate_limit.remaining == 0 =>
+            {
+                rate_limit.reset_in()
+            }
+            _ => {
+                return Err(SyncError::Forge(anyhow!(
+                    "GitHub API request '{route}' failed with {status}: {}",
+                    response.text().await.unwrap_or_default()
+                ))
+                .into())
+            }
+        };
+        wait_rate_limit(route, wait).await?;
+    }
+}
+
+/// Sleeps for `wait` before requesting `route` again, or fails if it takes
+/// longer than [`max_rate_limit_wait`].
+async fn wait_rate_limit(route: &str, wait: Duration) -> Result<()> {
+    let max_wait = max_rate_limit_wait();
+    if wait > max_wait {
+        return Err(SyncError::Forge(anyhow!(
+            "GitHub API rate limit exceeded while requesting '{route}', it will not reset for \
+             another {}s (longer than the maximum wait of {}s).",
+            wait.as_secs(),
+            max_wait.as_secs()
+        ))
+        .into());
+    }
+    warn!(
+        "GitHub API rate limit exceeded while requesting '{route}', waiting {}s...",
+        wait.as_secs()
+    );
+    tokio::time::sleep(wait).await;
+    Ok(())
+}
+
+/// Returns the current rate limit of the GitHub API, which does not count
+/// against the rate limit itself.
+pub async fn rate_limit(api: &Octocrab) -> Result<Option<RateLimit>> {
+    let response = request(api, "rate_limit", None).await?;
+    Ok(RateLimit::from_headers(response.headers()))
+}
+
+/// Makes sure the remaining rate limit can cover the `planned` requests.
+///
+/// We wait for the window to reset if it is soon enough, otherwise fail early
+/// instead of dying halfway.
+pub async fn ensure_rate_limit(api: &Octocrab, planned: u32) -> Result<()> {
+    let rate_limit = match rate_limit(api).await? {
+        Some(rate_limit) if rate_limit.remaining < planned => rate_limit,
+        _ => return Ok(()),
+    };
+    if planned > rate_limit.limit {
+        return Err(SyncError::Forge(anyhow!(
+            "{planned} GitHub API requests are planned, but the rate limit only allows {} \
+             requests per window.",
+            rate_limit.limit
+        ))
+        .into());
+    }
+    let wait = rate_limit.reset_in();
+    if wait > max_rate_limit_wait() {
+        return Err(SyncError::Forge(anyhow!(
+            "{planned} GitHub API requests are planned, but only {} of {} remain and the rate \
+             limit will not reset for another {}s.",
+            rate_limit.remaining,
+            rate_limit.limit,
+            wait.as_secs()
+        ))
+        .into());
+    }
+    warn!(
+        "{planned} GitHub API requests are planned, but only {} remain, waiting {}s for the \
+         rate limit to reset...",
+        rate_limit.remaining,
+        wait.as_secs()
+    );
+    tokio::time::sleep(wait).await;
+    Ok(())
+}
+
+/// Returns the number of the last page from the `Link` header.
+fn last_page(link: &str) -> Option<u32> {
+    link_urls(link)
+        .find(|(_, rel)| *rel == "last")
+        .and_then(|(url, _)| {
+            Url::parse(url)
+                .ok()?
+                .query_pairs()
+                .find(|(key, _)| key == "page")?
+                .1
+                .parse()
+                .ok()
+        })
+}
+
+/// Returns `true` if the `Link` header has a URL for the next page.
+pub(crate) fn has_next_page(link: &str) -> bool {
+    link_urls(link).any(|(_, rel)| rel == "next")
+}
+
+/// Returns all `(url, rel)` pairs of the `Link` header, which looks like:
+///
+/// ```text
+/// <https://api.github.com/repositories/1/tags?page=2>; rel="next", <...>; rel="last"
+/// ```
+fn link_urls(link: &str) -> impl Iterator<Item = (&str, &str)> {
+    link.split(',').filter_map(|link| {
+        let (url, rel) = link.split_once(';')?;
+        let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let rel = rel.trim().strip_prefix("rel=")?.trim_matches('"');
+        Some((url, rel))
+    })
+}
+
 pub trait TagsExt {
     fn names(self) -> Vec<String>;
 }
@@ -58,31 +683,117 @@ impl TagsExt for Vec<Tag> {
     }
 }
 
-pub struct Action;
+/// A GitHub instance, which is either github.com or a GitHub Enterprise
+/// Server.
+#[derive(Clone, PartialEq, Eq)]
+pub struct GitHubHost {
+    /// Base URL of the REST API, e.g. `https://api.github.com/`.
+    pub api_url: Url,
+    /// Base URL of the web pages, e.g. `https://github.com/`.
+    pub web_url: Url,
+    /// Token used to access this instance, instead of the `GITHUB_TOKEN`.
+    token: Option<String>,
+}
+
+impl GitHubHost {
+    /// Builds the instance from the web and API URLs and the token of the
+    /// `settings`, which default to github.com.
+    ///
+    /// If only the web URL is given, the API URL is derived from it in the
+    /// same way as GitHub Enterprise Server does, e.g. `https://ghe.example.com/api/v3/`.
+    pub fn new(settings: &HostSettings) -> Result<Self> {
+        fn parse_base_url(url: &str) -> Result<Url> {
+            // Make sure the URL can be joined with relative routes
+            let url = format!("{}/", url.trim_end_matches('/'));
+            Url::parse(&url).context(format!("Invalid URL: '{url}'"))
+        }
+
+        let github_com = Self::default();
+        let web_url = match HostSettings::non_empty(&settings.web_url) {
+            Some(web_url) => parse_base_url(web_url)?,
+            None => github_com.web_url.clone(),
+        };
+        let api_url = match HostSettings::non_empty(&settings.api_url) {
+            Some(api_url) => parse_base_url(api_url)?,
+            None if web_url == github_com.web_url => github_com.api_url,
+            None => web_url.join("api/v3/")?,
+        };
+        Ok(Self {
+            api_url,
+            web_url,
+            token: HostSettings::non_empty(&settings.github_token).map(str::to_string),
+        })
+    }
+
+    /// Returns the token used to access this instance.
+    pub fn token(&self) -> Result<String> {
+        match &self.token {
+            Some(token) => Ok(token.clone()),
+            None => Ok(get_env!("GITHUB_TOKEN")),
+        }
+    }
+
+    /// Returns the URL to clone the `owner`/`repo` repository over HTTPS.
+    ///
+    /// We build it from the configured web URL rather than trusting the
+    /// `clone_url` returned by the API, which may be rewritten by a proxy in
+    /// front of GitHub Enterprise Server.
+    pub fn clone_url(&self, owner: &str, repo: &str) -> Result<Url> {
+        Ok(self.web_url.join(&format!("{owner}/{repo}.git"))?)
+    }
+}
 
-impl Action {
-    pub(crate) fn set_output(key: &str, value: &str) {
-        println!("::set-output name={}::{}", key, value);
+impl Default for GitHubHost {
+    fn default() -> Self {
+        Self {
+            api_url: Url::parse("https://api.github.com/").unwrap(),
+            web_url: Url::parse("https://github.com/").unwrap(),
+            token: None,
+        }
     }
 }
 
-pub fn github_api() -> Result<Octocrab> {
-    Ok(Octocrab::builder()
-        .personal_token(github_token()?)
-        .build()?)
+impl Debug for GitHubHost {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        // Never print the token
+        fmt.debug_struct("GitHubHost")
+            .field("api_url", &self.api_url.as_str())
+            .field("web_url", &self.web_url.as_str())
+            .finish()
+    }
 }
 
-pub fn github_token() -> Result<String> {
-    Ok(get_env!("GITHUB_TOKEN"))
+/// Returns the GitHub API client used to access the `owner`/`repo`
+/// repository on the given `host`.
+///
+/// If a GitHub `app` is given, we authenticate as its installation on the
+/// repository, otherwise through the token of the `host`.
+pub async fn github_api(
+    host: &GitHubHost,
+    owner: &str,
+    repo: &str,
+    app: Option<&GitHubApp>,
+) -> Result<Octocrab> {
+    match app {
+        Some(app) => Ok(app.installation_api(host, owner, repo).await?.0),
+        None => Ok(Octocrab::builder()
+            .base_url(host.api_url.clone())?
+            .personal_token(host.token()?)
+            .build()?),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{test_async_fn, utils::RepoHandlerExt};
+    use reqwest::header::HeaderValue;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{test_async_fn, test_fn, utils::RepoHandlerExt};
 
     macro_rules! repo {
-        () => {
-            super::github_api()?.repos("vuejs", "vue")
+        ($api:ident) => {
+            GitHubRepo::new(&$api, "vuejs", "vue")
         };
     }
 
@@ -94,10 +805,11 @@ mod tests {
             ("0.11.8", "6c841059d2893d383befeed0caf8090d5f0e8b88"),
             ("0.6.0", "218557cdec830a629252f4a9e2643973dc1f1d2d"),
         ];
-        let tags = repo!().list_all_tags().await?;
+        let api = github_api(&GitHubHost::default(), "vuejs", "vue", None).await?;
+        let tags = repo!(api).list_all_tags().await?;
         // Make sure the results contain all expected tags
         for (name, sha) in EXPECTED_TAGS.iter() {
-            assert!(tags.iter().any(|t| t.name == *name && t.commit.sha == *sha));
+            assert!(tags.iter().any(|t| t.name == *name && t.sha == *sha));
         }
     });
 
@@ -107,12 +819,91 @@ mod tests {
             ("main", "60d268c4261a0b9c5125f308468b31996a8145ad", false),
             ("weex", "2acc12c9edb03329c4d9cddcca26e46c672a77bc", false),
         ];
-        let tags = repo!().list_all_branches().await?;
+        let api = github_api(&GitHubHost::default(), "vuejs", "vue", None).await?;
+        let tags = repo!(api).list_all_branches().await?;
         // Make sure the results contain all expected branches
         for (name, sha, protected) in EXPECTED_BRANCHES.iter() {
             assert!(tags.iter().any(|t|
-                t.name == *name && t.commit.sha == *sha && t.protected == *protected
+                t.name == *name && t.sha == *sha && t.protected == *protected
             ));
         }
     });
+
+    test_fn!(parse_link_header {
+        assert_eq!(last_page(""), None);
+        assert!(!has_next_page(""));
+
+        let link = "<https://api.github.com/repositories/1/tags?per_page=100&page=2>; rel=\"next\", \
+             <https://api.github.com/repositories/1/tags?per_page=100&page=7>; rel=\"last\"";
+        assert_eq!(last_page(link), Some(7));
+        assert!(has_next_page(link));
+    });
+
+    test_fn!(parse_rate_limit_headers {
+        let mut headers = HeaderMap::new();
+        assert_eq!(RateLimit::from_headers(&headers), None);
+
+        headers.insert("x-ratelimit-limit", HeaderValue::from_static("5000"));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("42"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("0"));
+        let rate_limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(rate_limit, RateLimit { limit: 5000, remaining: 42, reset: 0 });
+        // A window in the past is considered to be reset soon
+        assert_eq!(rate_limit.reset_in(), Duration::from_secs(1));
+    });
+
+    test_async_fn!(list_all_tags_cached {
+        let tmp_dir = tempdir()?;
+        let api = github_api(&GitHubHost::default(), "vuejs", "vue", None).await?;
+        let repo = repo!(api).with_cache(ResponseCache::new(tmp_dir.path()));
+        // The second listing is answered from the cache and must be the same
+        let tags = repo.list_all_tags().await?.names();
+        assert_eq!(repo.list_all_tags().await?.names(), tags);
+    });
+
+    test_async_fn!(rate_limit {
+        let rate_limit = rate_limit(&github_api(&GitHubHost::default(), "vuejs", "vue", None).await?).await?.unwrap();
+        assert!(rate_limit.remaining <= rate_limit.limit);
+    });
+
+    test_fn!(cache_key_per_host {
+        let api = Octocrab::builder().build()?;
+        let route = "repos/vuejs/vue/tags?per_page=100&page=1";
+        let key = |host: &GitHubHost| repo!(api).with_host(host.clone(), None).cache_key(route);
+        let github_com = GitHubHost {
+            token: Some("a".to_string()),
+            ..GitHubHost::default()
+        };
+        let ghe = GitHubHost {
+            api_url: Url::parse("https://ghe.example.com/api/v3/")?,
+            ..github_com.clone()
+        };
+        let other_token = GitHubHost {
+            token: Some("b".to_string()),
+            ..github_com.clone()
+        };
+        assert_eq!(key(&github_com), key(&github_com.clone()));
+        assert_ne!(key(&github_com), key(&ghe));
+        assert_ne!(key(&github_com), key(&other_token));
+    });
+
+    test_fn!(github_enterprise_host {
+        let host = GitHubHost::new(&HostSettings {
+            web_url: Some("https://ghe.example.com".to_string()),
+            ..HostSettings::default()
+        })?;
+        assert_eq!(host.api_url.as_str(), "https://ghe.example.com/api/v3/");
+        assert_eq!(
+            host.clone_url("owner", "repo")?.as_str(),
+            "https://ghe.example.com/owner/repo.git"
+        );
+
+        // github.com is the default
+        let host = GitHubHost::new(&HostSettings {
+            web_url: Some(String::new()),
+            ..HostSettings::default()
+        })?;
+        assert_eq!(host, GitHubHost::default());
+        assert_eq!(host.api_url.as_str(), "https://api.github.com/");
+    });
 }
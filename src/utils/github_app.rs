@@ -0,0 +1,172 @@
+//! Authentication as a GitHub App installation.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::EncodingKey;
+use log::{debug, info};
+use octocrab::{
+    models::{AppId, InstallationId},
+    Octocrab,
+};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::utils::{retry_async, Action, GitHubHost, SyncError};
+
+/// The tokens currently in use by installation ID, as the pairs of a
+/// configuration file may be synced to repositories of other installations.
+/// Each one is refreshed by [`refresh_installation_token`] before it expires.
+static INSTALLATION_TOKENS: Lazy<Mutex<HashMap<u64, InstallationToken>>> =
+    Lazy::new(Mutex::default);
+
+/// A GitHub App to authenticate as, instead of a personal access token.
+#[derive(Clone)]
+pub struct GitHubApp {
+    id: u64,
+    key: EncodingKey,
+    /// ID of the installation to authenticate as, which is looked up from
+    /// the repository if not given.
+    installation_id: Option<u64>,
+}
+
+/// An access token of a GitHub App installation.
+#[derive(Clone)]
+struct InstallationToken {
+    app: GitHubApp,
+    /// API client authenticated as the app itself.
+    app_api: Octocrab,
+    installation_id: u64,
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct Installation {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl GitHubApp {
+    /// Creates the GitHub App with the given `id` and PEM encoded private
+    /// `key`.
+    pub fn new(id: u64, key: &str, installation_id: Option<u64>) -> Result<Self> {
+        Ok(Self {
+            id,
+            key: EncodingKey::from_rsa_pem(key.as_bytes())
+                .context("The private key of the GitHub App must be a PEM encoded RSA key")?,
+            installation_id,
+        })
+    }
+
+    /// Returns an API client authenticated as the installation of this app
+    /// on the `owner`/`repo` repository of the given `host`, together with
+    /// the ID of the installation.
+    ///
+    /// The client refreshes its installation token by itself, and another
+    /// token is minted for the git credentials, see [`installation_token`].
+    pub async fn installation_api(
+        &self,
+        host: &GitHubHost,
+        owner: &str,
+        repo: &str,
+    ) -> Result<(Octocrab, u64)> {
+        let app_api = Octocrab::builder()
+            .base_url(host.api_url.clone())?
+            .app(AppId(self.id), self.key.clone())
+            .build()?;
+        let installation_id = match self.installation_id {
+            Some(id) => id,
+            None => {
+                let route = format!("repos/{owner}/{repo}/installation");
+                let installation: Installation = retry_async("Get app installation", || async {
+                    Ok(app_api.get(&route, None::<&()>).await?)
+                })
+                .await
+                .map_err(SyncError::request)
+                .context(format!(
+                    "GitHub App {} is not installed on '{owner}/{repo}'",
+                    self.id
+                ))?;
+                installation.id
+            }
+        };
+        info!(
+            "Authenticating as installation {installation_id} of GitHub App {}",
+            self.id
+        );
+
+        let token = self.create_token(&app_api, installation_id).await?;
+        INSTALLATION_TOKENS.lock().unwrap().insert(
+            installation_id,
+            InstallationToken {
+                app: self.clone(),
+                app_api: app_api.clone(),
+                installation_id,
+                token: token.token,
+                expires_at: token.expires_at,
+            },
+        );
+
+        Ok((
+            app_api.installation(InstallationId(installation_id)),
+            installation_id,
+        ))
+    }
+
+    async fn create_token(&self, app_api: &Octocrab, installation_id: u64) -> Result<AccessToken> {
+        let route = format!("app/installations/{installation_id}/access_tokens");
+        let token: AccessToken = retry_async("Create installation token", || async {
+            Ok(app_api.post(&route, None::<&()>).await?)
+        })
+        .await
+        .map_err(SyncError::request)
+        .context(format!(
+            "Failed to create a token for installation {installation_id}"
+        ))?;
+        Action::add_mask(&token.token);
+        debug!(
+            "Created token for installation {installation_id}, expires at {}",
+            token.expires_at
+        );
+        Ok(token)
+    }
+}
+
+/// Returns the current token of the installation with the given ID, if
+/// authenticated as it.
+pub fn installation_token(installation_id: u64) -> Option<String> {
+    INSTALLATION_TOKENS
+        .lock()
+        .unwrap()
+        .get(&installation_id)
+        .map(|token| token.token.clone())
+}
+
+/// Mints a new token for the installation with the given ID if the current
+/// one expires in less than five minutes, so that long runs can keep pushing.
+pub async fn refresh_installation_token(installation_id: u64) -> Result<()> {
+    let current = match INSTALLATION_TOKENS.lock().unwrap().get(&installation_id) {
+        Some(current) if current.expires_at - Utc::now() < Duration::minutes(5) => current.clone(),
+        _ => return Ok(()),
+    };
+    let token = current
+        .app
+        .create_token(&current.app_api, current.installation_id)
+        .await?;
+    INSTALLATION_TOKENS.lock().unwrap().insert(
+        installation_id,
+        InstallationToken {
+            token: token.token,
+            expires_at: token.expires_at,
+            ..current
+        },
+    );
+    Ok(())
+}
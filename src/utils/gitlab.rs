@@ -0,0 +1,481 @@
+//! A GitLab backend of [`Forge`], talking to the REST API v4.
+
+use std::{
+    fmt,
+    fmt::{Debug, Formatter},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use reqwest::{Client, Method, RequestBuilder, Response, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+
+use crate::utils::{
+    commit_status, retry_request, Branch, Credentials, Forge, HostSettings, Issue, PatchStatus,
+    PullRequest, RepoHandlerExt, SyncError, Tag, TagAnnotation, ISSUE_LABEL,
+};
+
+/// A GitLab instance, which is either gitlab.com or a self-hosted one.
+#[derive(Clone)]
+pub struct GitLabHost {
+    /// Base URL of the REST API, e.g. `https://gitlab.com/api/v4/`.
+    pub api_url: Url,
+    /// Base URL of the web pages, e.g. `https://gitlab.com/`.
+    pub web_url: Url,
+    /// Token used to access this instance.
+    token: Option<String>,
+}
+
+impl GitLabHost {
+    /// Builds the instance from the GitLab URLs and token of the `settings`,
+    /// which default to gitlab.com.
+    ///
+    /// They are separate from those of [`GitHubHost`], which default to the
+    /// GitHub instance running the workflow.
+    ///
+    /// [`GitHubHost`]: crate::utils::GitHubHost
+    pub fn new(settings: &HostSettings) -> Result<Self> {
+        fn parse_base_url(url: &str) -> Result<Url> {
+            let url = format!("{}/", url.trim_end_matches('/'));
+            Url::parse(&url).context(format!("Invalid URL: '{url}'"))
+        }
+
+        let web_url = parse_base_url(
+            HostSettings::non_empty(&settings.gitlab_url).unwrap_or("https://gitlab.com"),
+        )?;
+        let api_url = match HostSettings::non_empty(&settings.gitlab_api_url) {
+            Some(api_url) => parse_base_url(api_url)?,
+            None => web_url.join("api/v4/")?,
+        };
+        Ok(Self {
+            api_url,
+            web_url,
+            token: HostSettings::non_empty(&settings.gitlab_token).map(str::to_string),
+        })
+    }
+}
+
+impl Debug for GitLabHost {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        // Never print the token
+        fmt.debug_struct("GitLabHost")
+            .field("api_url", &self.api_url.as_str())
+            .field("web_url", &self.web_url.as_str())
+            .finish()
+    }
+}
+
+/// A project on GitLab.
+#[derive(Debug, Clone)]
+pub struct GitLabRepo {
+    client: Client,
+    host: GitLabHost,
+    /// Full path of the project, e.g. `group/subgroup/project`.
+    path: String,
+}
+
+impl GitLabRepo {
+    pub fn new(host: GitLabHost, path: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(),
+            host,
+            path: path.into(),
+        }
+    }
+
+    /// Returns the API route of the project followed by the given `route`.
+    fn project_route(&self, route: &str) -> String {
+        // The project path must be encoded as a single segment
+        format!("projects/{}/{route}", self.path.replace('/', "%2F"))
+    }
+
+    /// Sends a request built by `build` to the given API `route`, and returns
+    /// the successful response. Only the idempotent requests are retried.
+    async fn send(
+        &self,
+        method: Method,
+        route: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response> {
+        let url = self.host.api_url.join(route)?;
+        retry_request(&method, &format!("Request '{url}'"), || async {
+            let mut builder = self.client.request(method.clone(), url.clone());
+            if let Some(token) = &self.host.token {
+                builder = builder.header("PRIVATE-TOKEN", token);
+            }
+            Ok(build(builder).send().await?.error_for_status()?)
+        })
+        .await
+        .map_err(|error| SyncError::request(error).into())
+    }
+
+    /// Returns the merge request open for the `head` branch of the project, if
+    /// any.
+    async fn find_open_merge_request(&self, head: &str) -> Result<Option<MergeRequest>> {
+        let route = self.project_route("merge_requests");
+        let open_merge_requests = self
+            .send(Method::GET, &route, |builder| {
+                builder.query(&[("state", "opened"), ("source_branch", head)])
+            })
+            .await?
+            .json::<Vec<MergeRequest>>()
+            .await?;
+        Ok(open_merge_requests.into_iter().next())
+    }
+
+    /// Requests all pages of the given `route` of the project and returns
+    /// their items.
+    async fn all_pages<T: DeserializeOwned>(&self, route: &str) -> Result<Vec<T>> {
+        let route = self.project_route(route);
+        let mut page = 1u32;
+        let mut items = Vec::new();
+        loop {
+            let response = self
+                .send(Method::GET, &route, |builder| {
+                    builder.query(&[("per_page", 100), ("page", page)])
+                })
+                .await?;
+            let has_next_page = response
+                .headers()
+                .get("x-next-page")
+                .and_then(|value| value.to_str().ok())
+                .map_or(false, |value| !value.is_empty());
+            items.extend(response.json::<Vec<T>>().await?);
+            if !has_next_page {
+                break;
+            }
+            page += 1;
+        }
+        Ok(items)
+    }
+}
+
+#[derive(Deserialize)]
+struct GitLabTag {
+    name: String,
+    /// SHA of the tag object for annotated tags, or the commit otherwise.
+    target: String,
+    message: Option<String>,
+    commit: GitLabCommit,
+}
+
+#[derive(Deserialize)]
+struct GitLabBranch {
+    name: String,
+    protected: bool,
+    commit: GitLabCommit,
+}
+
+#[derive(Deserialize)]
+struct GitLabCommit {
+    id: String,
+    committed_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct MergeRequest {
+    iid: u64,
+    web_url: Url,
+}
+
+#[derive(Deserialize)]
+struct GitLabIssue {
+    iid: u64,
+    web_url: Url,
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: u64,
+}
+
+impl From<GitLabTag> for Tag {
+    fn from(tag: GitLabTag) -> Self {
+        let annotation = (tag.target != tag.commit.id).then(|| TagAnnotation {
+            sha: tag.target,
+            message: tag.message.unwrap_or_default(),
+            tagger_date: None,
+        });
+        Self {
+            name: tag.name,
+            sha: tag.commit.id,
+            commit_date: tag.commit.committed_date,
+            annotation,
+        }
+    }
+}
+
+impl GitLabRepo {
+    /// Looks up the IDs of the users with the given user `names`, which are
+    /// required to assign merge requests.
+    async fn user_ids(&self, names: &[String]) -> Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for name in names {
+            let users = self
+                .send(Method::GET, "users", |builder| {
+                    builder.query(&[("username", name)])
+                })
+                .await?
+                .json::<Vec<User>>()
+                .await?;
+            match users.first() {
+                Some(user) => ids.push(user.id),
+                None => warn!("GitLab user '{name}' not found, skipping"),
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[async_trait]
+impl RepoHandlerExt for GitLabRepo {
+    async fn list_all_tags(&self) -> Result<Vec<Tag>> {
+        let tags = self.all_pages::<GitLabTag>("repository/tags").await?;
+        Ok(tags.into_iter().map(Tag::from).collect())
+    }
+
+    async fn list_all_branches(&self) -> Result<Vec<Branch>> {
+        let branches = self
+            .all_pages::<GitLabBranch>("repository/branches")
+            .await?;
+        Ok(branches
+            .into_iter()
+            .map(|branch| Branch {
+                name: branch.name,
+                sha: branch.commit.id,
+                protected: branch.protected,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Forge for GitLabRepo {
+    fn clone_url(&self) -> Result<Url> {
+        Ok(self.host.web_url.join(&format!("{}.git", self.path))?)
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        let credentials = |token: &String| ("oauth2".to_string(), token.clone());
+        Ok(self.host.token.as_ref().map(credentials))
+    }
+
+    fn compare_url(&self, base: &str, head: &str) -> Option<Url> {
+        let route = format!("{}/-/compare/{base}...{head}", self.path);
+        self.host.web_url.join(&route).ok()
+    }
+
+    fn branch_url(&self, branch: &str) -> Option<Url> {
+        let route = format!("{}/-/tree/{branch}", self.path);
+        self.host.web_url.join(&route).ok()
+    }
+
+    async fn open_pull_request(&self, request: &PullRequest) -> Result<Url> {
+        let route = self.project_route("merge_requests");
+        let mut body = json!({
+            "source_branch": request.head,
+            "target_branch": request.base,
+            "title": request.title,
+            "description": request.body,
+        });
+        if !request.assignees.is_empty() {
+            body["assignee_ids"] = json!(self.user_ids(&request.assignees).await?);
+        }
+        if !request.reviewers.is_empty() {
+            body["reviewer_ids"] = json!(self.user_ids(&request.reviewers).await?);
+        }
+        let labels = request.labels.join(",");
+        let merge_request = match self.find_open_merge_request(&request.head).await? {
+            Some(merge_request) => {
+                debug!(
+                    "Updating merge request !{} of '{}'",
+                    merge_request.iid, request.head
+                );
+                body["add_labels"] = json!(labels);
+                let route = format!("{route}/{}", merge_request.iid);
+                self.send(Method::PUT, &route, |builder| builder.json(&body))
+                    .await?;
+                merge_request
+            }
+            None => {
+                body["labels"] = json!(labels);
+                match self
+                    .send(Method::POST, &route, |builder| builder.json(&body))
+                    .await
+                {
+                    Ok(response) => response.json::<MergeRequest>().await?,
+                    // The merge request may have been opened even though the
+                    // request failed, so it is looked up instead of opened
+                    // again
+                    Err(error) => self
+                        .find_open_merge_request(&request.head)
+                        .await
+                        .ok()
+                        .flatten()
+                        .ok_or(error)?,
+                }
+            }
+        };
+        Ok(merge_request.web_url)
+    }
+
+    async fn create_release(&self, tag: &str, target: &str, name: &str, body: &str) -> Result<Url> {
+        let body = json!({
+            "tag_name": tag,
+            "ref": target,
+            "name": name,
+            "description": body,
+        });
+        let route = self.project_route("releases");
+        if let Err(error) = self
+            .send(Method::POST, &route, |builder| builder.json(&body))
+            .await
+        {
+            // The release may have been created even though the request
+            // failed, so it is looked up instead of created again
+            let route = format!("{route}/{}", tag.replace('/', "%2F"));
+            self.send(Method::GET, &route, |builder| builder)
+                .await
+                .map_err(|_| error)?;
+        }
+        Ok(self
+            .host
+            .web_url
+            .join(&format!("{}/-/releases/{tag}", self.path))?)
+    }
+
+    async fn find_open_issue(&self, title: &str) -> Result<Option<Issue>> {
+        let route = format!("issues?state=opened&labels={ISSUE_LABEL}");
+        let issues = self.all_pages::<GitLabIssue>(&route).await?;
+        Ok(issues
+            .into_iter()
+            .find(|issue| issue.title == title)
+            .map(|issue| Issue {
+                number: issue.iid,
+                url: issue.web_url,
+            }))
+    }
+
+    async fn open_issue(&self, title: &str, body: &str) -> Result<Issue> {
+        let route = self.project_route("issues");
+        let body = json!({ "title": title, "description": body, "labels": ISSUE_LABEL });
+        match self
+            .send(Method::POST, &route, |builder| builder.json(&body))
+            .await
+        {
+            Ok(response) => {
+                let issue = response.json::<GitLabIssue>().await?;
+                Ok(Issue {
+                    number: issue.iid,
+                    url: issue.web_url,
+                })
+            }
+            // The issue may have been opened even though the request failed,
+            // so it is looked up instead of opened again
+            Err(error) => self
+                .find_open_issue(title)
+                .await
+                .ok()
+                .flatten()
+                .ok_or(error),
+        }
+    }
+
+    async fn comment_issue(&self, issue: &Issue, body: &str) -> Result<()> {
+        let route = self.project_route(&format!("issues/{}/notes", issue.number));
+        let body = json!({ "body": body });
+        self.send(Method::POST, &route, |builder| builder.json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn close_issue(&self, issue: &Issue) -> Result<()> {
+        let route = self.project_route(&format!("issues/{}", issue.number));
+        let body = json!({ "state_event": "close" });
+        self.send(Method::PUT, &route, |builder| builder.json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn set_commit_status(
+        &self,
+        sha: &str,
+        patch: Option<PatchStatus>,
+        target_url: Option<&str>,
+    ) -> Result<()> {
+        let route = self.project_route(&format!("statuses/{sha}"));
+        let (name, description) = commit_status(patch);
+        let body = json!({
+            "state": "success",
+            "name": name,
+            "description": description,
+            "target_url": target_url,
+        });
+        self.send(Method::POST, &route, |builder| builder.json(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_branch(&self, branch: &str) -> Result<()> {
+        // The branch name must be encoded as a single segment too
+        let route = self.project_route(&format!(
+            "repository/branches/{}",
+            branch.replace('/', "%2F")
+        ));
+        self.send(Method::DELETE, &route, |builder| builder).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(parse_tags {
+        let tags = serde_json::from_value::<Vec<GitLabTag>>(json!([
+            {
+                "name": "v1.0",
+                "target": "a",
+                "message": null,
+                "commit": { "id": "a", "committed_date": "2022-09-01T08:00:00.000+08:00" }
+            },
+            {
+                "name": "v2.0",
+                "target": "b",
+                "message": "Release v2.0",
+                "commit": { "id": "c", "committed_date": null }
+            }
+        ]))?
+        .into_iter()
+        .map(Tag::from)
+        .collect::<Vec<_>>();
+
+        assert_eq!(tags[0].sha, "a");
+        assert_eq!(tags[0].commit_date.unwrap().to_rfc3339(), "2022-09-01T00:00:00+00:00");
+        assert!(tags[0].annotation.is_none());
+
+        // Annotated tags target the tag object rather than the commit
+        let annotation = tags[1].annotation.as_ref().unwrap();
+        assert_eq!(tags[1].sha, "c");
+        assert_eq!(annotation.sha, "b");
+        assert_eq!(annotation.message, "Release v2.0");
+    });
+
+    test_fn!(gitlab_urls {
+        let host = GitLabHost::new(&HostSettings {
+            gitlab_url: Some("https://gitlab.example.com/".to_string()),
+            ..HostSettings::default()
+        })?;
+        let repo = GitLabRepo::new(host, "group/subgroup/project");
+        assert_eq!(repo.host.api_url.as_str(), "https://gitlab.example.com/api/v4/");
+        assert_eq!(
+            repo.clone_url()?.as_str(),
+            "https://gitlab.example.com/group/subgroup/project.git"
+        );
+    });
+}
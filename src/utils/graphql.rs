@@ -0,0 +1,319 @@
+//! Listing through the GitHub GraphQL API, which returns the target metadata
+//! of tags in bulk.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::debug;
+use reqwest::Method;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use crate::utils::{
+    retry_async, send, Branch, GitHubRepo, RepoHandlerExt, SyncError, Tag, TagAnnotation,
+};
+
+const LIST_TAGS_QUERY: &str = r#"
+query($owner: String!, $name: String!, $cursor: String) {
+  repository(owner: $owner, name: $name) {
+    refs(refPrefix: "refs/tags/", first: 100, after: $cursor) {
+      pageInfo { hasNextPage endCursor }
+      nodes {
+        name
+        target {
+          __typename
+          oid
+          ... on Commit { committedDate }
+          ... on Tag {
+            message
+            tagger { date }
+            target { __typename oid ... on Commit { committedDate } }
+          }
+        }
+      }
+    }
+  }
+}
+"#;
+
+/// A repository on GitHub, whose tags are listed through the GraphQL API.
+///
+/// The REST API only returns the name and commit SHA of each tag, so that
+/// any other metadata would cost one more request per tag. Here we get the
+/// commit date and annotation of 100 tags per query instead.
+pub struct GitHubGraphQlRepo<'octo> {
+    repo: GitHubRepo<'octo>,
+}
+
+impl<'octo> GitHubGraphQlRepo<'octo> {
+    pub fn new(repo: GitHubRepo<'octo>) -> Self {
+        Self { repo }
+    }
+
+    /// Sends the GraphQL `query` with the given `variables` and returns the
+    /// data of the response.
+    async fn query<T: DeserializeOwned>(&self, query: &str, variables: Value) -> Result<T> {
+        // The queries only read, so they are retried although they are sent
+        // with a `POST`
+        let body = json!({ "query": query, "variables": variables });
+        let response = retry_async("GraphQL query", || async {
+            Ok(send(self.repo.api(), Method::POST, "graphql", |builder| {
+                builder.json(&body)
+            })
+            .await?
+            .json::<GraphQlResponse<T>>()
+            .await?)
+        })
+        .await?;
+
+        if let Some(errors) = response.errors.filter(|errors| !errors.is_empty()) {
+            return Err(SyncError::Forge(anyhow!(
+                "GitHub GraphQL query failed: {}",
+                errors
+                    .into_iter()
+                    .map(|error| error.message)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ))
+            .into());
+        }
+        response.data.context("GitHub GraphQL response has no data")
+    }
+}
+
+#[async_trait]
+impl RepoHandlerExt for GitHubGraphQlRepo<'_> {
+    async fn list_all_tags(&self) -> Result<Vec<Tag>> {
+        let mut cursor = None::<String>;
+        let mut tags = Vec::new();
+        loop {
+            let data = self
+                .query::<RepositoryData>(
+                    LIST_TAGS_QUERY,
+                    json!({
+                        "owner": self.repo.owner(),
+                        "name": self.repo.name(),
+                        "cursor": cursor,
+                    }),
+                )
+                .await?;
+            let refs = data
+                .repository
+                .context(format!(
+                    "Repository '{}/{}' not found",
+                    self.repo.owner(),
+                    self.repo.name()
+                ))?
+                .refs;
+            tags.extend(refs.nodes.into_iter().filter_map(RefNode::into_tag));
+
+            if !refs.page_info.has_next_page {
+                break;
+            }
+            cursor = refs.page_info.end_cursor;
+        }
+        Ok(tags)
+    }
+
+    /// The branches have no extra metadata worth a GraphQL query, so we just
+    /// list them through the REST API.
+    async fn list_all_branches(&self) -> Result<Vec<Branch>> {
+        self.repo.list_all_branches().await
+    }
+}
+
+#[derive(Deserialize)]
+struct GraphQlResponse<T> {
+    data: Option<T>,
+    errors: Option<Vec<GraphQlError>>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RepositoryData {
+    repository: Option<Repository>,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    refs: Refs,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Refs {
+    page_info: PageInfo,
+    nodes: Vec<RefNode>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RefNode {
+    name: String,
+    target: RefTarget,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "__typename")]
+enum RefTarget {
+    #[serde(rename_all = "camelCase")]
+    Commit {
+        oid: String,
+        committed_date: Option<DateTime<Utc>>,
+    },
+    Tag {
+        oid: String,
+        message: String,
+        tagger: Option<Tagger>,
+        target: TagTarget,
+    },
+    /// A tag that points to a tree or blob, which cannot be synced.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct Tagger {
+    date: Option<DateTime<Utc>>,
+}
+
+/// The target of an annotated tag, which is only synced if it is a commit.
+#[derive(Deserialize)]
+#[serde(tag = "__typename")]
+enum TagTarget {
+    #[serde(rename_all = "camelCase")]
+    Commit {
+        oid: String,
+        committed_date: Option<DateTime<Utc>>,
+    },
+    /// A tag of another tag, or of a tree or blob.
+    #[serde(other)]
+    Other,
+}
+
+impl RefNode {
+    fn into_tag(self) -> Option<Tag> {
+        match self.target {
+            RefTarget::Commit {
+                oid,
+                committed_date,
+            } => Some(Tag {
+                name: self.name,
+                sha: oid,
+                commit_date: committed_date,
+                annotation: None,
+            }),
+            RefTarget::Tag {
+                oid,
+                message,
+                tagger,
+                target:
+                    TagTarget::Commit {
+                        oid: target_oid,
+                        committed_date,
+                    },
+            } => Some(Tag {
+                name: self.name,
+                sha: target_oid,
+                commit_date: committed_date,
+                annotation: Some(TagAnnotation {
+                    sha: oid,
+                    message,
+                    tagger_date: tagger.and_then(|tagger| tagger.date),
+                }),
+            }),
+            RefTarget::Tag {
+                target: TagTarget::Other,
+                ..
+            }
+            | RefTarget::Other => {
+                debug!("Skip tag '{}' which does not point to a commit", self.name);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_async_fn, test_fn,
+        utils::{github_api, GitHubHost},
+    };
+
+    test_fn!(parse_ref_nodes {
+        let refs = serde_json::from_value::<Refs>(json!({
+            "pageInfo": { "hasNextPage": false, "endCursor": null },
+            "nodes": [
+                {
+                    "name": "v1.0",
+                    "target": {
+                        "__typename": "Commit",
+                        "oid": "a",
+                        "committedDate": "2022-09-01T08:00:00+08:00"
+                    }
+                },
+                {
+                    "name": "v2.0",
+                    "target": {
+                        "__typename": "Tag",
+                        "oid": "b",
+                        "message": "Release v2.0",
+                        "tagger": { "date": "2022-09-02T00:00:00Z" },
+                        "target": {
+                            "__typename": "Commit",
+                            "oid": "c",
+                            "committedDate": "2022-09-01T00:00:00Z"
+                        }
+                    }
+                },
+                {
+                    "name": "nested",
+                    "target": {
+                        "__typename": "Tag",
+                        "oid": "e",
+                        "message": "Tag of a tag",
+                        "tagger": null,
+                        "target": { "__typename": "Tag", "oid": "b" }
+                    }
+                },
+                { "name": "tree", "target": { "__typename": "Tree", "oid": "d" } }
+            ]
+        }))?;
+        let tags = refs.nodes.into_iter().filter_map(RefNode::into_tag).collect::<Vec<_>>();
+
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].sha, "a");
+        assert_eq!(tags[0].commit_date.unwrap().to_rfc3339(), "2022-09-01T00:00:00+00:00");
+        assert!(tags[0].annotation.is_none());
+
+        // Annotated tags are peeled to the commit
+        let annotation = tags[1].annotation.as_ref().unwrap();
+        assert_eq!(tags[1].sha, "c");
+        assert_eq!(annotation.sha, "b");
+        assert_eq!(annotation.message, "Release v2.0");
+        // Tags of tags are skipped rather than synced to the inner tag
+        assert!(tags.iter().all(|tag| tag.name != "nested"));
+    });
+
+    test_async_fn!(list_all_tags {
+        let api = github_api(&GitHubHost::default(), "vuejs", "vue", None).await?;
+        let repo = GitHubGraphQlRepo::new(GitHubRepo::new(&api, "vuejs", "vue"));
+        let tags = repo.list_all_tags().await?;
+        // The same tag as the REST API, but with the commit date
+        let tag = tags.iter().find(|tag| tag.name == "v2.7.10").unwrap();
+        assert_eq!(tag.sha, "ee57d9fd1d51abe245c6c37e6f8f2d45977b929e");
+        assert!(tag.commit_date.is_some());
+    });
+}
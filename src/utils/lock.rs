@@ -0,0 +1,296 @@
+//! Lease on the head repository, so that two runs overlapping do not push the
+//! same tags at the same time.
+//!
+//! The lease is a commit of the `refs/tags-sync/lock` ref of the head
+//! repository, whose message holds the ID of the run and when the lease
+//! expires. As the ref is pushed without force, the push is a compare and
+//! swap: creating the ref fails if another run holds it, and breaking a stale
+//! lease only succeeds on top of the very commit found stale.
+//!
+//! For the same reason, a lease is never deleted, but released by a child
+//! commit, which only succeeds if the lease of the run is still there.
+
+use std::{cell::RefCell, env, process, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use git2::{
+    AutotagOption, Commit, ErrorCode, FetchOptions, Oid, PushOptions, Repository, Signature,
+};
+use log::{debug, info, warn};
+
+use crate::{
+    consts::*,
+    utils::{proxy_auto, remote_callbacks, retry, Credentials, SyncError},
+};
+
+/// Ref of the lease in the head repository.
+pub const LOCK_REF: &str = "refs/tags-sync/lock";
+
+/// Ref where the lease of the head repository is fetched to in the clone.
+const FETCHED_LOCK_REF: &str = "refs/remotes/origin/tags-sync/lock";
+
+/// A lease held on the head repository by this run.
+#[derive(Debug)]
+pub struct Lock {
+    /// The commit of the lease pushed by this run.
+    commit: Oid,
+    run: String,
+}
+
+/// Holder of a lease, as recorded in the message of its commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Lease {
+    run: String,
+    expires: DateTime<Utc>,
+    /// Whether the run released the lease, which is free then.
+    released: bool,
+}
+
+impl Lease {
+    fn message(&self) -> String {
+        format!(
+            "tags-sync {}\n\nrun: {}\nexpires: {}\n",
+            match self.released {
+                true => "unlock",
+                false => "lock",
+            },
+            self.run,
+            self.expires.to_rfc3339()
+        )
+    }
+
+    fn parse(message: &str) -> Option<Self> {
+        let field = |key: &str| {
+            message
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+        };
+        Some(Self {
+            run: field("run")?.to_string(),
+            expires: DateTime::parse_from_rfc3339(field("expires")?)
+                .ok()?
+                .with_timezone(&Utc),
+            released: message.starts_with("tags-sync unlock"),
+        })
+    }
+}
+
+impl Lock {
+    /// Acquires the lease of the origin of the `repo` for the `run`, which
+    /// expires after the `timeout`.
+    ///
+    /// Fails if another run holds a lease which has not expired yet. An
+    /// expired lease is broken, as its run is considered dead.
+    pub fn acquire(
+        repo: &Repository,
+        credentials: Option<&Credentials>,
+        run: &str,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let now = Utc::now();
+        let held = fetch_lock(repo, credentials)?;
+        let parent = match &held {
+            Some((_, Some(lease)))
+                if !lease.released && lease.run != run && lease.expires > now =>
+            {
+                bail!(
+                    "Run {} holds the lock of the head repository until {}",
+                    lease.run,
+                    lease.expires.to_rfc3339()
+                )
+            }
+            Some((commit, lease)) => {
+                match lease {
+                    Some(lease) if lease.released => {}
+                    Some(lease) if lease.run == run => {
+                        info!("Renewing the lock of the head repository")
+                    }
+                    Some(lease) => warn!(
+                        "Breaking the lock of run {}, which expired at {}",
+                        lease.run,
+                        lease.expires.to_rfc3339()
+                    ),
+                    None => warn!("Breaking the lock at '{commit}', which cannot be read"),
+                }
+                Some(repo.find_commit(*commit)?)
+            }
+            None => None,
+        };
+
+        let lease = Lease {
+            run: run.to_string(),
+            expires: now + chrono::Duration::from_std(timeout)?,
+            released: false,
+        };
+        let commit = commit_lease(repo, &lease, parent.as_ref())?;
+        push_lock(repo, credentials)
+            .context("Another run took the lock of the head repository first")?;
+        info!(
+            "Locked the head repository until {}",
+            lease.expires.to_rfc3339()
+        );
+
+        Ok(Self {
+            commit,
+            run: run.to_string(),
+        })
+    }
+
+    /// Releases the lease of the origin of the `repo`, unless another run
+    /// broke it in the meantime.
+    pub fn release(self, repo: &Repository, credentials: Option<&Credentials>) -> Result<()> {
+        let lease = Lease {
+            run: self.run,
+            expires: Utc::now(),
+            released: true,
+        };
+        commit_lease(repo, &lease, Some(&repo.find_commit(self.commit)?))?;
+        push_lock(repo, credentials)
+            .context("The lock of the head repository was broken by another run")?;
+        info!("Unlocked the head repository");
+        Ok(())
+    }
+}
+
+/// Returns the ID of this run, which is the one of the workflow run on GitHub
+/// Actions.
+pub fn lock_run_id() -> String {
+    env::var("GITHUB_RUN_ID")
+        .ok()
+        .filter(|run| !run.is_empty())
+        .unwrap_or_else(|| format!("local-{}", process::id()))
+}
+
+/// Fetches the lease of the origin of the `repo`, and returns its commit
+/// together with its holder if the message can be read.
+fn fetch_lock(
+    repo: &Repository,
+    credentials: Option<&Credentials>,
+) -> Result<Option<(Oid, Option<Lease>)>> {
+    // The lease fetched by an earlier run is removed first, as the fetch
+    // leaves it alone if the origin has none
+    match repo.find_reference(FETCHED_LOCK_REF) {
+        Ok(mut reference) => reference.delete()?,
+        Err(error) if error.code() == ErrorCode::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+    retry("Fetch lock", || {
+        Ok(repo.find_remote(ORIGIN)?.fetch(
+            &[format!("+{LOCK_REF}:{FETCHED_LOCK_REF}")],
+            Some(
+                FetchOptions::new()
+                    .download_tags(AutotagOption::None)
+                    .remote_callbacks(remote_callbacks(credentials)),
+            ),
+            None,
+        )?)
+    })
+    .map_err(SyncError::git)?;
+
+    let commit = match repo.find_reference(FETCHED_LOCK_REF) {
+        Ok(reference) => reference.peel_to_commit()?,
+        Err(error) if error.code() == ErrorCode::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let lease = commit.message().and_then(Lease::parse);
+    debug!("Lock commit='{}', lease={lease:?}", commit.id());
+    Ok(Some((commit.id(), lease)))
+}
+
+/// Commits the `lease` on top of the `parent` lease if any, as the lease of
+/// the `repo` to be pushed.
+fn commit_lease(repo: &Repository, lease: &Lease, parent: Option<&Commit>) -> Result<Oid> {
+    let signature = Signature::now("tags-sync", "tags-sync@users.noreply.github.com")?;
+    let tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+    let parents = parent.into_iter().collect::<Vec<_>>();
+    let commit = repo.commit(
+        None,
+        &signature,
+        &signature,
+        &lease.message(),
+        &tree,
+        &parents,
+    )?;
+    repo.reference(LOCK_REF, commit, true, "Commit lease")?;
+    Ok(commit)
+}
+
+/// Pushes the lease of the `repo` to its origin without force, failing if the
+/// origin rejects it.
+fn push_lock(repo: &Repository, credentials: Option<&Credentials>) -> Result<()> {
+    let refspec = format!("{LOCK_REF}:{LOCK_REF}");
+    let rejected = RefCell::new(None);
+    let mut callbacks = remote_callbacks(credentials);
+    callbacks.push_update_reference(|reference, status| {
+        debug!("Pushed reference='{reference}', status='{status:?}'");
+        *rejected.borrow_mut() = status.map(str::to_string);
+        Ok(())
+    });
+
+    let mut options = PushOptions::new();
+    options
+        .proxy_options(proxy_auto())
+        .remote_callbacks(callbacks);
+    retry("Push lock", || {
+        Ok(repo
+            .find_remote(ORIGIN)?
+            .push(&[&refspec], Some(&mut options))?)
+    })
+    .map_err(SyncError::git)?;
+    drop(options);
+
+    match rejected.into_inner() {
+        Some(status) => {
+            Err(SyncError::Git(anyhow!("The head repository rejected the lock: {status}")).into())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(parse_lease {
+        let mut lease = Lease {
+            run: "1234".to_string(),
+            expires: DateTime::parse_from_rfc3339("2022-10-01T12:00:00Z")?.with_timezone(&Utc),
+            released: false,
+        };
+        assert_eq!(Lease::parse(&lease.message()), Some(lease.clone()));
+        lease.released = true;
+        assert_eq!(Lease::parse(&lease.message()), Some(lease));
+        assert_eq!(Lease::parse("tags-sync lock\n\nrun: 1234\n"), None);
+    });
+
+    test_fn!(lock_head_repository {
+        let origin_dir = tempdir()?;
+        Repository::init_bare(origin_dir.path())?;
+        let origin_url = origin_dir.path().to_str().unwrap();
+        let clone = |dir: &tempfile::TempDir| Repository::clone(origin_url, dir.path());
+        let (first_dir, second_dir) = (tempdir()?, tempdir()?);
+        let (first, second) = (clone(&first_dir)?, clone(&second_dir)?);
+        let timeout = Duration::from_secs(60);
+
+        let lock = Lock::acquire(&first, None, "1", timeout)?;
+        let error = Lock::acquire(&second, None, "2", timeout).unwrap_err();
+        assert!(error.to_string().starts_with("Run 1 holds the lock"));
+        lock.release(&first, None)?;
+
+        // A lease which has expired is broken by the next run, so that the
+        // run which held it can no longer release it
+        let stale = Lock::acquire(&first, None, "1", Duration::ZERO)?;
+        let lock = Lock::acquire(&second, None, "2", timeout)?;
+        assert!(Lock::acquire(&first, None, "1", timeout).is_err());
+        assert!(stale.release(&first, None).is_err());
+        assert!(Lock::acquire(&first, None, "1", timeout).is_err());
+        lock.release(&second, None)?;
+        let released = fetch_lock(&first, None)?.and_then(|(_, lease)| lease);
+        assert!(released.map_or(false, |lease| lease.released && lease.run == "2"));
+        Lock::acquire(&first, None, "1", timeout)?;
+    });
+}
@@ -1,10 +1,42 @@
+pub use action::*;
+pub use branch_template::*;
+pub use cache::*;
+pub use check_run::*;
 pub use commit::*;
+pub use error::*;
+pub use forge::*;
 pub use git::*;
+pub use gitea::*;
 pub use github::*;
+pub use github_app::*;
+pub use gitlab::*;
+pub use graphql::*;
+pub use lock::*;
+pub use models::*;
+pub use plain_git::*;
+pub use pull_request::*;
+pub use report::*;
+pub use retry::*;
 
 #[macro_use]
 mod env;
+mod action;
+mod branch_template;
+mod cache;
+mod check_run;
 mod commit;
+mod error;
+mod forge;
 mod git;
+mod gitea;
 mod github;
+mod github_app;
+mod gitlab;
+mod graphql;
+mod lock;
+mod models;
+mod plain_git;
+mod pull_request;
+mod report;
+mod retry;
 mod test;
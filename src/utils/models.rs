@@ -0,0 +1,63 @@
+//! Models shared by all implementations of [`RepoHandlerExt`].
+//!
+//! [`RepoHandlerExt`]: crate::utils::RepoHandlerExt
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A tag of a repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tag {
+    /// Name of the tag, without the `refs/tags/` prefix.
+    pub name: String,
+    /// SHA of the commit that the tag points to.
+    pub sha: String,
+    /// Date of the commit that the tag points to, if it has been requested.
+    pub commit_date: Option<DateTime<Utc>>,
+    /// Annotation of the tag, only available for annotated tags and if it has
+    /// been requested.
+    pub annotation: Option<TagAnnotation>,
+}
+
+/// Annotation of an annotated tag.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagAnnotation {
+    /// SHA of the tag object itself.
+    pub sha: String,
+    /// Message of the tag.
+    pub message: String,
+    /// Date when the tag was created.
+    pub tagger_date: Option<DateTime<Utc>>,
+}
+
+/// A branch of a repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Branch {
+    /// Name of the branch, without the `refs/heads/` prefix.
+    pub name: String,
+    /// SHA of the commit that the branch points to.
+    pub sha: String,
+    /// Whether the branch is protected.
+    pub protected: bool,
+}
+
+impl From<octocrab::models::repos::Tag> for Tag {
+    fn from(tag: octocrab::models::repos::Tag) -> Self {
+        Self {
+            name: tag.name,
+            sha: tag.commit.sha,
+            commit_date: None,
+            annotation: None,
+        }
+    }
+}
+
+impl From<octocrab::models::repos::Branch> for Branch {
+    fn from(branch: octocrab::models::repos::Branch) -> Self {
+        Self {
+            name: branch.name,
+            sha: branch.commit.sha,
+            protected: branch.protected,
+        }
+    }
+}
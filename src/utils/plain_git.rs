@@ -0,0 +1,242 @@
+//! A backend of [`Forge`] for repositories without any forge API, which only
+//! talks to the remote over git.
+
+use std::{
+    fmt,
+    fmt::{Debug, Formatter},
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use git2::{Direction, Remote};
+use reqwest::Url;
+
+use crate::utils::{
+    proxy_auto, remote_callbacks, retry, Branch, Credentials, Forge, HostSettings, Issue,
+    PatchStatus, PullRequest, RepoHandlerExt, Tag, TagAnnotation,
+};
+
+/// A repository specified by a git URL, e.g. `https://git.kernel.org/…`,
+/// `ssh://git@example.com/repo.git` or `file:///path/to/repo.git`.
+///
+/// The tags and branches are listed from the refs advertised by the remote,
+/// like `git ls-remote` does, so that no commit dates are available.
+#[derive(Clone)]
+pub struct PlainGitRepo {
+    url: Url,
+    /// User name and password for HTTPS remotes, if any.
+    credentials: Option<Credentials>,
+}
+
+impl PlainGitRepo {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            credentials: None,
+        }
+    }
+
+    /// Sets the git user name and password of the `settings` as the
+    /// credentials, if both are given.
+    pub fn with_credentials(mut self, settings: &HostSettings) -> Self {
+        self.credentials = HostSettings::non_empty(&settings.git_username)
+            .zip(settings.git_password.as_deref())
+            .map(|(user, password)| (user.to_string(), password.to_string()));
+        self
+    }
+
+    /// Returns all `(name, oid)` pairs of the refs advertised by the remote.
+    async fn list_refs(&self) -> Result<Vec<(String, String)>> {
+        let (url, credentials) = (self.url.clone(), self.credentials.clone());
+        tokio::task::spawn_blocking(move || {
+            retry(&format!("List refs of '{url}'"), || {
+                let mut remote = Remote::create_detached(url.as_str())?;
+                let connection = remote.connect_auth(
+                    Direction::Fetch,
+                    Some(remote_callbacks(credentials.as_ref())),
+                    Some(proxy_auto()),
+                )?;
+                Ok(connection
+                    .list()?
+                    .iter()
+                    .map(|head| (head.name().to_string(), head.oid().to_string()))
+                    .collect())
+            })
+        })
+        .await?
+    }
+}
+
+impl Debug for PlainGitRepo {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        // Never print the password
+        fmt.debug_struct("PlainGitRepo")
+            .field("url", &self.url.as_str())
+            .finish()
+    }
+}
+
+/// Collects the tags from the advertised `refs`, where an annotated tag comes
+/// with a `^{}` peeled ref pointing to its commit.
+fn tags_from_refs(refs: &[(String, String)]) -> Vec<Tag> {
+    refs.iter()
+        .filter_map(|(name, oid)| {
+            let tag = name.strip_prefix("refs/tags/")?;
+            if tag.ends_with("^{}") {
+                return None;
+            }
+            let peeled = format!("{name}^{{}}");
+            let commit = refs.iter().find(|(name, _)| *name == peeled);
+            Some(Tag {
+                name: tag.to_string(),
+                sha: commit.map_or(oid, |(_, commit)| commit).clone(),
+                commit_date: None,
+                annotation: commit.map(|_| TagAnnotation {
+                    sha: oid.clone(),
+                    // The message is only available after fetching the tag
+                    message: String::new(),
+                    tagger_date: None,
+                }),
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl RepoHandlerExt for PlainGitRepo {
+    async fn list_all_tags(&self) -> Result<Vec<Tag>> {
+        Ok(tags_from_refs(&self.list_refs().await?))
+    }
+
+    async fn list_all_branches(&self) -> Result<Vec<Branch>> {
+        let refs = self.list_refs().await?;
+        Ok(refs
+            .into_iter()
+            .filter_map(|(name, sha)| {
+                Some(Branch {
+                    name: name.strip_prefix("refs/heads/")?.to_string(),
+                    sha,
+                    protected: false,
+                })
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Forge for PlainGitRepo {
+    fn clone_url(&self) -> Result<Url> {
+        Ok(self.url.clone())
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        Ok(self.credentials.clone())
+    }
+
+    fn compare_url(&self, _: &str, _: &str) -> Option<Url> {
+        None
+    }
+
+    fn branch_url(&self, _: &str) -> Option<Url> {
+        None
+    }
+
+    async fn open_pull_request(&self, _: &PullRequest) -> Result<Url> {
+        bail!(
+            "Cannot open pull requests on '{}' without a forge API",
+            self.url
+        )
+    }
+
+    async fn create_release(&self, _: &str, _: &str, _: &str, _: &str) -> Result<Url> {
+        bail!(
+            "Cannot create releases on '{}' without a forge API",
+            self.url
+        )
+    }
+
+    async fn find_open_issue(&self, _: &str) -> Result<Option<Issue>> {
+        Ok(None)
+    }
+
+    async fn open_issue(&self, _: &str, _: &str) -> Result<Issue> {
+        bail!("Cannot open issues on '{}' without a forge API", self.url)
+    }
+
+    async fn comment_issue(&self, _: &Issue, _: &str) -> Result<()> {
+        bail!(
+            "Cannot comment on issues on '{}' without a forge API",
+            self.url
+        )
+    }
+
+    async fn close_issue(&self, _: &Issue) -> Result<()> {
+        bail!("Cannot close issues on '{}' without a forge API", self.url)
+    }
+
+    async fn set_commit_status(
+        &self,
+        _: &str,
+        _: Option<PatchStatus>,
+        _: Option<&str>,
+    ) -> Result<()> {
+        bail!(
+            "Cannot set commit statuses on '{}' without a forge API",
+            self.url
+        )
+    }
+
+    async fn delete_branch(&self, _: &str) -> Result<()> {
+        bail!(
+            "Cannot delete branches on '{}' without a forge API",
+            self.url
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Repository, Signature};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        consts::*,
+        test_async_fn,
+        utils::{RepoExt, TagsExt},
+    };
+
+    test_async_fn!(sync_local_repos {
+        let temp_dir = tempdir()?;
+        let base_path = temp_dir.path().join("base.git");
+        let head_path = temp_dir.path().join("head.git");
+
+        // A bare base repository with a lightweight and an annotated tag
+        let base = Repository::init_bare(&base_path)?;
+        let signature = Signature::now("tags-sync", "tags-sync@example.com")?;
+        let tree = base.find_tree(base.treebuilder(None)?.write()?)?;
+        let commit = base.commit(Some("refs/heads/main"), &signature, &signature, "init", &tree, &[])?;
+        let commit = base.find_object(commit, None)?;
+        base.tag_lightweight("v1.0", &commit, false)?;
+        let annotation = base.tag("v2.0", &commit, &signature, "Release v2.0", false)?;
+        Repository::init_bare(&head_path)?;
+
+        let base = PlainGitRepo::new(Url::from_directory_path(&base_path).unwrap());
+        let head = PlainGitRepo::new(Url::from_directory_path(&head_path).unwrap());
+        let tags = base.list_all_tags().await?;
+        assert_eq!(tags.clone().names(), ["v1.0", "v2.0"]);
+        assert!(tags.iter().all(|tag| tag.sha == commit.id().to_string()));
+        assert_eq!(tags[1].annotation.as_ref().unwrap().sha, annotation.to_string());
+
+        // Sync a tag from the base repository to the head one over git only
+        let clone = Repository::clone(head.clone_url()?.as_str(), temp_dir.path().join("clone"))?;
+        clone.remote(UPSTREAM, base.clone_url()?.as_str())?;
+        clone.fetch_upstream_tags(&["v2.0"], None)?;
+        clone.checkout_tag("v2.0", "upstream/v2.0")?;
+        clone.push_head(None)?;
+
+        let branches = head.list_all_branches().await?;
+        assert_eq!(branches.len(), 1);
+        assert_eq!(branches[0].name, "upstream/v2.0");
+    });
+}
@@ -0,0 +1,129 @@
+//! Pull requests opened for the synced branches.
+
+use reqwest::Url;
+
+const DEFAULT_TITLE: &str = "Sync {tag}";
+const DEFAULT_BODY: &str = "\
+Synced the upstream tag `{tag}` as `{branch}`.
+
+- Upstream changes: {compare_url}
+- Patch: {patch}";
+
+/// A pull request (or merge request) to open, or to update if one is already
+/// open for the same `head` branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequest {
+    /// Branch to merge from.
+    pub head: String,
+    /// Branch to merge into.
+    pub base: String,
+    pub title: String,
+    pub body: String,
+    /// Names of the labels to add.
+    pub labels: Vec<String>,
+    /// User names of the assignees to add.
+    pub assignees: Vec<String>,
+    /// User names of the reviewers to request.
+    pub reviewers: Vec<String>,
+}
+
+/// Template of the pull requests opened for the synced branches.
+///
+/// The title and body may contain the following placeholders:
+///
+/// - `{tag}`: name of the synced tag.
+/// - `{branch}`: name of the synced branch.
+/// - `{compare_url}`: link to compare the tag against the target branch in
+///   the upstream repository.
+/// - `{patch}`: result of applying the patch.
+#[derive(Debug, Clone)]
+pub struct PullRequestTemplate {
+    /// Branch of the head repository to open the pull requests against.
+    pub base: String,
+    title: String,
+    body: String,
+    labels: Vec<String>,
+    assignees: Vec<String>,
+    reviewers: Vec<String>,
+}
+
+impl PullRequestTemplate {
+    /// Creates the template of the pull requests against the `base` branch,
+    /// with the default title and body unless given.
+    ///
+    /// The `labels`, `assignees` and `reviewers` are comma separated lists.
+    pub fn new(
+        base: String,
+        title: Option<String>,
+        body: Option<String>,
+        labels: &str,
+        assignees: &str,
+        reviewers: &str,
+    ) -> Self {
+        fn list(items: &str) -> Vec<String> {
+            items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        }
+
+        Self {
+            base,
+            title: title.unwrap_or_else(|| DEFAULT_TITLE.into()),
+            body: body.unwrap_or_else(|| DEFAULT_BODY.into()),
+            labels: list(labels),
+            assignees: list(assignees),
+            reviewers: list(reviewers),
+        }
+    }
+
+    /// Renders the pull request of the `branch` synced from the `tag`.
+    pub fn render(
+        &self,
+        tag: &str,
+        branch: &str,
+        compare_url: Option<&Url>,
+        patch: &str,
+    ) -> PullRequest {
+        let compare_url = compare_url.map_or("unavailable", Url::as_str);
+        let render = |template: &str| {
+            template
+                .replace("{tag}", tag)
+                .replace("{branch}", branch)
+                .replace("{compare_url}", compare_url)
+                .replace("{patch}", patch)
+        };
+        PullRequest {
+            head: branch.to_string(),
+            base: self.base.clone(),
+            title: render(&self.title),
+            body: render(&self.body),
+            labels: self.labels.clone(),
+            assignees: self.assignees.clone(),
+            reviewers: self.reviewers.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(render_pull_request {
+        let template = PullRequestTemplate::new("main".to_string(), None, None, "sync", "", " octocat, ");
+        let compare_url = Url::parse("https://github.com/torvalds/linux/compare/main...v6.0")?;
+        let request = template.render("v6.0", "sync-v6.0", Some(&compare_url), "Applied cleanly");
+
+        assert_eq!(request.head, "sync-v6.0");
+        assert_eq!(request.base, "main");
+        assert_eq!(request.title, "Sync v6.0");
+        assert!(request.body.starts_with("Synced the upstream tag `v6.0` as `sync-v6.0`."));
+        assert!(request.body.contains(compare_url.as_str()));
+        assert!(request.body.ends_with("- Patch: Applied cleanly"));
+        assert_eq!(request.labels, ["sync"]);
+        assert_eq!(request.reviewers, ["octocat"]);
+    });
+}
@@ -0,0 +1,494 @@
+//! Structured results of the stages, which are rendered into the job summary.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use crate::utils::{ErrorKind, PatchStatus, Tag};
+
+/// Result of detecting the new tags of the base repository.
+#[derive(Debug, Clone, Default)]
+pub struct DetectReport {
+    /// Name of the pair of repositories in the configuration file, if any.
+    pub pair: Option<String>,
+    /// Number of tags in the base repository.
+    pub base_tags: usize,
+    /// The new tags, which match the filter and have no synced branch in the
+    /// head repository yet.
+    pub new_tags: Vec<Tag>,
+    pub duration: Duration,
+}
+
+/// Serializes the new tags of all the `reports` into the new tags file, which
+/// hands them over to the sync when it runs in another step.
+pub fn new_tags_json(reports: &[DetectReport]) -> Result<String> {
+    let tags = reports
+        .iter()
+        .flat_map(|report| {
+            report.new_tags.iter().map(|tag| NewTag {
+                pair: report.pair.clone(),
+                tag: tag.clone(),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&NewTags {
+        version: NEW_TAGS_VERSION,
+        tags,
+    })?)
+}
+
+/// A new tag listed in the new tags file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewTag {
+    /// Name of the pair of repositories that the tag belongs to, which is
+    /// absent without a configuration file.
+    #[serde(default)]
+    pub pair: Option<String>,
+    #[serde(flatten)]
+    pub tag: Tag,
+}
+
+/// Parses the new tags from the new tags file written by [`new_tags_json`].
+pub fn parse_new_tags(json: &str) -> Result<Vec<NewTag>> {
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+
+    let Version { version } =
+        serde_json::from_str(json).context("The new tags file has no version")?;
+    if version != NEW_TAGS_VERSION {
+        bail!(
+            "Version {version} of the new tags file is not supported, expected {NEW_TAGS_VERSION}"
+        );
+    }
+    let new_tags = serde_json::from_str::<NewTags>(json).context("The new tags file is invalid")?;
+    Ok(new_tags.tags)
+}
+
+/// State of the tags of the base repository, compared against the synced
+/// branches of the head repository.
+#[derive(Debug, Clone, Default)]
+pub struct StatusReport {
+    /// Names of the filtered tags which have a synced branch.
+    pub synced: Vec<String>,
+    /// Names of the filtered tags which have no synced branch yet.
+    pub pending: Vec<String>,
+    /// Names of the synced branches whose tag no longer exists.
+    pub stale: Vec<String>,
+}
+
+/// Result of syncing the new tags to the head repository.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Name of the pair of repositories in the configuration file, if any.
+    pub pair: Option<String>,
+    /// Results of the tags, in the order they were synced.
+    pub tags: Vec<TagReport>,
+    pub duration: Duration,
+    /// The failures of the sync, in the order they happened.
+    pub failures: Vec<Failure>,
+    /// How the failures of the tags affect the sync.
+    pub policy: FailurePolicy,
+    /// Whether the branches were only synced in the local clone, without
+    /// being pushed.
+    pub dry_run: bool,
+}
+
+impl SyncReport {
+    /// Returns the tags that were synced.
+    pub fn synced(&self) -> impl Iterator<Item = &TagReport> {
+        self.tags
+            .iter()
+            .filter(|tag| matches!(tag.outcome, TagOutcome::Synced { .. }))
+    }
+
+    /// Returns whether the sync failed according to its [`FailurePolicy`].
+    ///
+    /// Failing to start the sync always fails it, as no tag could be synced.
+    pub fn failed(&self) -> bool {
+        match self.policy {
+            FailurePolicy::BestEffort => self.failures.iter().any(|failure| failure.tag.is_none()),
+            FailurePolicy::FailFast | FailurePolicy::FailAtEnd => !self.failures.is_empty(),
+        }
+    }
+}
+
+/// Serializes the results of the tags of all the `reports` in the format
+/// described by `results.schema.json`.
+pub fn results_json(reports: &[SyncReport]) -> Result<String> {
+    let tags = reports
+        .iter()
+        .flat_map(|report| {
+            report
+                .tags
+                .iter()
+                .map(|tag| TagResult::new(report.pair.as_deref(), tag))
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&Results {
+        version: RESULTS_VERSION,
+        dry_run: reports.iter().any(|report| report.dry_run),
+        tags,
+    })?)
+}
+
+/// How the failures of the tags affect the sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum FailurePolicy {
+    /// The first failing tag stops the sync, and the later tags are skipped.
+    #[default]
+    FailFast,
+    /// Every tag is synced, and the failing ones are only reported as
+    /// warnings.
+    BestEffort,
+    /// Every tag is synced, and the sync fails at the end if any of them
+    /// failed.
+    FailAtEnd,
+}
+
+/// Result of syncing a single tag.
+#[derive(Debug, Clone)]
+pub struct TagReport {
+    pub tag: String,
+    pub branch: String,
+    pub outcome: TagOutcome,
+    /// SHA of the commit that the tag points to.
+    pub upstream_sha: Option<String>,
+    /// SHA of the head commit of the pushed branch.
+    pub head_sha: Option<String>,
+    /// Whether the branch was already pushed with the same content by an
+    /// interrupted run, and so was not pushed again.
+    pub resumed: bool,
+    /// The step being run, which is the failing one if the tag failed.
+    pub step: SyncStep,
+    pub duration: Duration,
+    /// Web URL of the synced branch.
+    pub branch_url: Option<Url>,
+    /// Web URL comparing the tag against the base branch of the pull
+    /// request.
+    pub compare_url: Option<Url>,
+    /// Web URL of the pull request opened for the branch.
+    pub pull_request: Option<Url>,
+    /// Web URL of the release created for the tag.
+    pub release: Option<Url>,
+    /// Whether a commit status was set on the branch.
+    pub commit_status: bool,
+}
+
+impl TagReport {
+    pub fn new(tag: &str, branch: String) -> Self {
+        Self {
+            tag: tag.to_string(),
+            branch,
+            outcome: TagOutcome::Skipped,
+            upstream_sha: None,
+            head_sha: None,
+            resumed: false,
+            step: SyncStep::Checkout,
+            duration: Duration::ZERO,
+            branch_url: None,
+            compare_url: None,
+            pull_request: None,
+            release: None,
+            commit_status: false,
+        }
+    }
+}
+
+/// Result of syncing a tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagOutcome {
+    /// The tag was synced, with the patch applied if any.
+    Synced { patch: Option<PatchStatus> },
+    /// The tag was not synced, because an earlier tag failed and the sync
+    /// fails fast.
+    Skipped,
+    /// The tag failed to sync.
+    Failed {
+        step: SyncStep,
+        /// The error, with all its causes.
+        error: String,
+        /// Paths of the files that the patch conflicts with.
+        conflicts: Vec<String>,
+    },
+}
+
+/// A step of detecting or syncing the tags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SyncStep {
+    Detect,
+    Clone,
+    Fetch,
+    /// Locking the head repository against concurrent runs.
+    Lock,
+    Checkout,
+    Patch,
+    Push,
+    /// Anything done after the branch is pushed, such as opening its pull
+    /// request or creating its release.
+    Hook,
+}
+
+impl SyncStep {
+    /// Returns the title of the annotation about this step failing for the
+    /// `tag`, if any.
+    pub fn title(self, tag: Option<&str>) -> String {
+        match tag {
+            Some(tag) => format!("Step '{self}' failed for tag {tag}"),
+            None => format!("Step '{self}' failed"),
+        }
+    }
+}
+
+/// A failure of the sync, or of one of its tags.
+#[derive(Debug)]
+pub struct Failure {
+    pub step: SyncStep,
+    /// The tag being synced, unless the sync failed to start.
+    pub tag: Option<String>,
+    pub error: Error,
+}
+
+impl Failure {
+    /// Returns a function turning an error into a failure of the `step`,
+    /// before any tag is synced.
+    pub fn at<E: Into<Error>>(step: SyncStep) -> impl FnOnce(E) -> Self {
+        move |error| Self {
+            step,
+            tag: None,
+            error: error.into(),
+        }
+    }
+
+    pub fn title(&self) -> String {
+        self.step.title(self.tag.as_deref())
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::of(&self.error, self.step)
+    }
+}
+
+/// Version of the format of the new tags file, which is bumped on breaking
+/// changes.
+pub const NEW_TAGS_VERSION: u32 = 1;
+
+/// Content of the new tags file.
+#[derive(Serialize, Deserialize)]
+struct NewTags {
+    version: u32,
+    tags: Vec<NewTag>,
+}
+
+/// Version of the format of the results file, which is bumped on breaking
+/// changes.
+pub const RESULTS_VERSION: u32 = 1;
+
+/// Content of the results file.
+#[derive(Serialize)]
+struct Results<'a> {
+    version: u32,
+    /// Whether nothing was pushed to the head repositories.
+    dry_run: bool,
+    tags: Vec<TagResult<'a>>,
+}
+
+#[derive(Serialize)]
+struct TagResult<'a> {
+    /// Name of the pair of repositories, or `null` without a configuration
+    /// file.
+    pair: Option<&'a str>,
+    name: &'a str,
+    upstream_sha: Option<&'a str>,
+    branch: &'a str,
+    head_sha: Option<&'a str>,
+    resumed: bool,
+    /// `synced`, `skipped` or `failed`.
+    status: &'static str,
+    /// How the patch was applied, or `none` without a patch, or `null` if the
+    /// tag was not synced.
+    patch: Option<PatchResult>,
+    failed_step: Option<SyncStep>,
+    error: Option<&'a str>,
+    conflicts: &'a [String],
+    hooks: Hooks<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PatchResult {
+    Clean,
+    ThreeWay,
+    None,
+}
+
+#[derive(Serialize)]
+struct Hooks<'a> {
+    commit_status: bool,
+    pull_request: Option<&'a str>,
+    release: Option<&'a str>,
+}
+
+impl<'a> TagResult<'a> {
+    fn new(pair: Option<&'a str>, report: &'a TagReport) -> Self {
+        let (status, patch, failed_step, error, conflicts) = match &report.outcome {
+            TagOutcome::Synced { patch } => {
+                let patch = match patch {
+                    Some(PatchStatus::Clean) => PatchResult::Clean,
+                    Some(PatchStatus::ThreeWay) => PatchResult::ThreeWay,
+                    None => PatchResult::None,
+                };
+                ("synced", Some(patch), None, None, &[][..])
+            }
+            TagOutcome::Skipped => ("skipped", None, None, None, &[][..]),
+            TagOutcome::Failed {
+                step,
+                error,
+                conflicts,
+            } => (
+                "failed",
+                None,
+                Some(*step),
+                Some(error.as_str()),
+                &conflicts[..],
+            ),
+        };
+        Self {
+            pair,
+            name: &report.tag,
+            upstream_sha: report.upstream_sha.as_deref(),
+            branch: &report.branch,
+            head_sha: report.head_sha.as_deref(),
+            resumed: report.resumed,
+            status,
+            patch,
+            failed_step,
+            error,
+            conflicts,
+            hooks: Hooks {
+                commit_status: report.commit_status,
+                pull_request: report.pull_request.as_ref().map(Url::as_str),
+                release: report.release.as_ref().map(Url::as_str),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_fn;
+
+    test_fn!(hand_over_new_tags {
+        let tag = |name: &str, sha: &str| Tag {
+            name: name.to_string(),
+            sha: sha.to_string(),
+            commit_date: None,
+            annotation: None,
+        };
+        let reports = [
+            DetectReport {
+                base_tags: 3,
+                new_tags: vec![tag("v1.0", "a"), tag("v2.0", "b")],
+                ..Default::default()
+            },
+            DetectReport {
+                pair: Some("git".to_string()),
+                new_tags: vec![tag("v2.38.0", "c")],
+                ..Default::default()
+            },
+        ];
+
+        let json = new_tags_json(&reports)?;
+        let new_tags = parse_new_tags(&json)?;
+        assert_eq!(new_tags.len(), 3);
+        assert_eq!(new_tags[1], NewTag { pair: None, tag: tag("v2.0", "b") });
+        assert_eq!(new_tags[2].pair.as_deref(), Some("git"));
+        // The files written without a configuration file have no pair
+        let json = r#"{"version": 1, "tags": [{"name": "v1.0", "sha": "a"}]}"#;
+        assert_eq!(parse_new_tags(json)?[0].tag, tag("v1.0", "a"));
+        // The file of older versions lists the names line by line
+        assert!(parse_new_tags("v1.0\nv2.0\n").is_err());
+        let json = json.replace("\"version\": 1", "\"version\": 2");
+        let error = parse_new_tags(&json).unwrap_err();
+        assert!(error.to_string().starts_with("Version 2 of the new tags file"));
+    });
+
+    test_fn!(fail_by_policy {
+        let failure = |tag: Option<&str>| Failure {
+            step: SyncStep::Push,
+            tag: tag.map(str::to_string),
+            error: Error::msg("Connection reset"),
+        };
+        let mut report = SyncReport::default();
+        for policy in ["fail-fast", "fail-at-end", "best-effort"] {
+            report.policy = policy.parse()?;
+            assert!(!report.failed());
+        }
+
+        report.failures.push(failure(Some("v1.0")));
+        for (policy, failed) in [("fail-fast", true), ("fail-at-end", true), ("best-effort", false)] {
+            report.policy = policy.parse()?;
+            assert_eq!(report.failed(), failed, "{policy}");
+        }
+        // Nothing is synced if the sync fails to start, whatever the policy
+        report.failures.push(failure(None));
+        assert!(report.failed());
+    });
+
+    test_fn!(serialize_results {
+        let mut synced = TagReport::new("v1.0", "sync-v1.0".to_string());
+        synced.outcome = TagOutcome::Synced {
+            patch: Some(PatchStatus::ThreeWay),
+        };
+        synced.upstream_sha = Some("a".repeat(40));
+        synced.head_sha = Some("b".repeat(40));
+        synced.pull_request = Some(Url::parse("https://github.com/o/r/pull/1")?);
+        let mut failed = TagReport::new("v2.0", "sync-v2.0".to_string());
+        failed.outcome = TagOutcome::Failed {
+            step: SyncStep::Patch,
+            error: "Patch does not apply to a.txt".to_string(),
+            conflicts: vec!["a.txt".to_string()],
+        };
+        let report = SyncReport {
+            pair: Some("linux".to_string()),
+            tags: vec![synced, failed],
+            ..Default::default()
+        };
+
+        let results = serde_json::from_str::<Value>(&results_json(&[report])?)?;
+        assert_eq!(results["version"], RESULTS_VERSION);
+        assert_eq!(results["dry_run"], false);
+        assert_eq!(results["tags"][0], json!({
+            "pair": "linux",
+            "name": "v1.0",
+            "upstream_sha": "a".repeat(40),
+            "branch": "sync-v1.0",
+            "head_sha": "b".repeat(40),
+            "resumed": false,
+            "status": "synced",
+            "patch": "three_way",
+            "failed_step": null,
+            "error": null,
+            "conflicts": [],
+            "hooks": {
+                "commit_status": false,
+                "pull_request": "https://github.com/o/r/pull/1",
+                "release": null,
+            },
+        }));
+        assert_eq!(results["tags"][1]["status"], "failed");
+        assert_eq!(results["tags"][1]["patch"], Value::Null);
+        assert_eq!(results["tags"][1]["failed_step"], "patch");
+        assert_eq!(results["tags"][1]["conflicts"], json!(["a.txt"]));
+    });
+}
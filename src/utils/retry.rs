@@ -0,0 +1,288 @@
+//! A shared retry policy for all network operations.
+
+use std::{
+    future::Future,
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Error, Result};
+use git2::{ErrorClass, ErrorCode};
+use log::warn;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use reqwest::Method;
+
+use crate::utils::SyncError;
+
+/// The policy used by [`retry`] and [`retry_async`], which is set once by
+/// [`RetryPolicy::install`].
+static RETRY_POLICY: OnceCell<RetryPolicy> = OnceCell::new();
+
+/// Number of retries performed by all network operations so far.
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled on each subsequent retry.
+    pub base_delay: Duration,
+    /// Upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Makes this policy the one used by all network operations, before any
+    /// of them runs.
+    pub fn install(self) {
+        let _ = RETRY_POLICY.set(self);
+    }
+
+    /// Returns the policy used by all network operations, which is the
+    /// default one unless another one is installed.
+    fn current() -> &'static Self {
+        RETRY_POLICY.get_or_init(Self::default)
+    }
+
+    /// Returns the delay before the `retry`-th retry (starting from 1).
+    ///
+    /// The delay grows exponentially, and a random half of it is jittered away
+    /// so that concurrent jobs do not hammer the server at the same time.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Decides whether to retry after the `attempt`-th attempt failed with
+    /// the given `error`, and returns the delay to wait if so.
+    fn next_delay(&self, operation: &str, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt >= self.attempts || !is_retryable(error) {
+            return None;
+        }
+        let delay = self.delay(attempt);
+        RETRIES.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "{operation} failed (attempt {attempt}/{}), retrying in {:.1}s: {error:#}",
+            self.attempts,
+            delay.as_secs_f64()
+        );
+        Some(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Runs the blocking `operation` with the installed [`RetryPolicy`].
+pub fn retry<T, F>(operation: &str, f: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    retry_with(RetryPolicy::current(), operation, f)
+}
+
+/// Runs the asynchronous `operation` with the installed [`RetryPolicy`].
+pub async fn retry_async<T, F, Fut>(operation: &str, f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    retry_async_with(RetryPolicy::current(), operation, f).await
+}
+
+/// Runs the asynchronous API request `operation` with the installed
+/// [`RetryPolicy`] if its `method` is idempotent, or only once otherwise.
+///
+/// A request creating something, such as a `POST`, may have succeeded on the
+/// server even though it failed on our side, so sending it again could create
+/// the same thing twice.
+pub async fn retry_request<T, F, Fut>(method: &Method, operation: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    match is_idempotent(method) {
+        true => retry_async(operation, f).await,
+        false => f().await,
+    }
+}
+
+/// Returns `true` if sending a request with the `method` twice has the same
+/// effect as sending it once.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    )
+}
+
+fn retry_with<T, F>(policy: &RetryPolicy, operation: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) => match policy.next_delay(operation, attempt, &error) {
+                Some(delay) => thread::sleep(delay),
+                None => return Err(error),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+async fn retry_async_with<T, F, Fut>(policy: &RetryPolicy, operation: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) => match policy.next_delay(operation, attempt, &error) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(error),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// Returns the number of retries performed by all network operations so far.
+pub fn retries() -> usize {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the `error` is caused by a transient failure, such as a
+/// reset connection or a `5xx` response, so the operation is worth retrying.
+pub fn is_retryable(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        // A classified error shows the error it wraps instead of itself
+        if let Some(error) = cause.downcast_ref::<SyncError>() {
+            return error.wrapped().map_or(false, is_retryable);
+        }
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            // GitHub answers some outages with an HTML page instead of a JSON
+            // error, which octocrab then fails to decode
+            return error.is_timeout()
+                || error.is_connect()
+                || error.is_request()
+                || error.is_body()
+                || error.is_decode()
+                || error.status().map_or(false, |status| {
+                    status.is_server_error() || status.as_u16() == 408 || status.as_u16() == 429
+                });
+        }
+        if let Some(error) = cause.downcast_ref::<git2::Error>() {
+            return matches!(
+                error.class(),
+                ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Os
+            ) && !matches!(error.code(), ErrorCode::Auth | ErrorCode::Certificate);
+        }
+        if let Some(error) = cause.downcast_ref::<octocrab::GitHubError>() {
+            let message = error.message.to_lowercase();
+            return message.contains("rate limit")
+                || message.contains("server error")
+                || message.contains("timed out");
+        }
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            return matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::UnexpectedEof
+            );
+        }
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::{test_async_fn, test_fn};
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+
+    fn transient_error() -> Error {
+        git2::Error::new(ErrorCode::GenericError, ErrorClass::Net, "connection reset").into()
+    }
+
+    test_fn!(retry_transient_error {
+        let mut calls = 0;
+        let result = retry_with(&POLICY, "Transient", || {
+            calls += 1;
+            if calls < 3 { Err(transient_error()) } else { Ok(calls) }
+        })?;
+        assert_eq!(result, 3);
+        // Also once classified by the layer where it happened
+        assert!(is_retryable(&SyncError::git(transient_error()).into()));
+    });
+
+    test_fn!(retry_gives_up {
+        let mut calls = 0;
+        let result = retry_with(&POLICY, "Always failing", || -> Result<()> {
+            calls += 1;
+            Err(transient_error())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, POLICY.attempts);
+    });
+
+    test_async_fn!(retry_fatal_error {
+        let mut calls = 0;
+        let result = retry_async_with(&POLICY, "Fatal", || {
+            calls += 1;
+            async { Err::<(), _>(anyhow!("bad credentials")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    });
+
+    test_fn!(idempotent_methods {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(is_idempotent(&Method::DELETE));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    });
+
+    test_fn!(exponential_delay {
+        let policy = RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+        };
+        let delay = policy.delay(2);
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+        // The delay never exceeds the upper bound
+        assert!(policy.delay(4) <= Duration::from_secs(3));
+    });
+}
@@ -39,3 +39,73 @@ macro_rules! test_async_fn {
         }
     };
 }
+
+/// A stand-in HTTP server for the tests of the forge APIs, which answers each
+/// request with the canned response of the first matching route.
+#[cfg(test)]
+pub struct StubServer {
+    pub url: reqwest::Url,
+    requests: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl StubServer {
+    /// Starts the server on a random local port.
+    ///
+    /// Each route is a `(request, headers, body)` tuple, where the `request`
+    /// is matched against the start of the request line, e.g. `GET /tags`.
+    pub async fn start(routes: Vec<(&'static str, &'static str, String)>) -> anyhow::Result<Self> {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = reqwest::Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        let requests = std::sync::Arc::<std::sync::Mutex<Vec<String>>>::default();
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // Read the whole request, including the body if any
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                while let Ok(read @ 1..) = stream.read(&mut buffer).await {
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                let (key, value) = line.split_once(':')?;
+                                key.eq_ignore_ascii_case("content-length")
+                                    .then(|| value.trim().parse::<usize>().ok())?
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break;
+                        }
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let response = match routes.iter().find(|(route, ..)| request.starts_with(route)) {
+                    Some((_, headers, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n{headers}\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: \
+                             close\r\n\r\n"
+                        .to_string(),
+                };
+                received.lock().unwrap().push(request);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        Ok(Self { url, requests })
+    }
+
+    /// Returns all requests received so far, including their headers.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}